@@ -8,6 +8,7 @@ use junta::prelude::*;
 use junta_service::prelude::*;
 use serde_cbor::Value;
 use std::error::Error;
+use std::time::Instant;
 
 pub trait RequestProtocolService {
     type Item: serde::Serialize;
@@ -81,10 +82,12 @@ where
                 let name = name.to_string();
                 let binary = ctx.binary();
                 let client = ctx.client().clone();
+                let start = Instant::now();
                 OneOfTwo::First(
                     self.service
                         .execute(ctx.into_parent().with_message(req).0)
                         .then(move |ret| {
+                            client.metrics().observe_request(&name, start.elapsed());
                             //let value = serde_cbor::to_value(ret).unwrap();
                             let msg = match ret {
                                 Ok(value) => {
@@ -95,12 +98,12 @@ where
                             };
 
                             let event = Event::new(id, msg);
-                            if binary {
+                            let sent = if binary {
                                 client.send_binary(&event)
                             } else {
                                 client.send_text(&event)
-                            }
-                            
+                            };
+                            sent.map(|_| ())
                         })
                         // .and_then(move |ret| {
                         //     let value = serde_cbor::to_value(ret).unwrap();
@@ -0,0 +1,32 @@
+use futures::prelude::*;
+use junta::prelude::*;
+use junta_service::prelude::*;
+use slog::{Drain, Logger};
+
+fn main() {
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let logger = Logger::root(drain, slog::o! {});
+
+    let mut runtime = tokio::runtime::Builder::new().build().unwrap();
+
+    let fut = Client::connect("ws://127.0.0.1:2794")
+        .unwrap()
+        .logger(logger)
+        .serve(
+            runtime.executor(),
+            service_fn(|ctx: Context<ClientEvent>| {
+                slog::info!(ctx.client().logger(), "got event {:?}", ctx.message());
+                Ok(())
+            }),
+        )
+        .and_then(|client| {
+            client
+                .send(MessageContent::Text("Hello".to_string()))
+                .and_then(move |_| client.close())
+        });
+
+    runtime.block_on(fut).unwrap();
+    runtime.shutdown_on_idle().wait().unwrap();
+}
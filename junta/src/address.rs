@@ -0,0 +1,68 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tokio::net::TcpStream;
+
+/// The address of a connected peer.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Address {
+    Inet(SocketAddr),
+    /// A unix socket peer, with its path if it is bound to one.
+    Unix(Option<PathBuf>),
+}
+
+impl Address {
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.as_inet().map(|addr| addr.ip())
+    }
+
+    pub fn as_inet(&self) -> Option<&SocketAddr> {
+        match self {
+            Address::Inet(addr) => Some(addr),
+            Address::Unix(_) => None,
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        match self {
+            Address::Unix(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Inet(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Inet(addr) => write!(f, "{}", addr),
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Address::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+/// A stream accepted by a listener.
+pub(crate) trait Peer {
+    fn peer(&self) -> io::Result<Address>;
+}
+
+impl Peer for TcpStream {
+    fn peer(&self) -> io::Result<Address> {
+        self.peer_addr().map(Address::Inet)
+    }
+}
+
+#[cfg(unix)]
+impl Peer for tokio::net::UnixStream {
+    fn peer(&self) -> io::Result<Address> {
+        let addr = self.peer_addr()?;
+        Ok(Address::Unix(addr.as_pathname().map(|p| p.to_path_buf())))
+    }
+}
@@ -1,44 +1,34 @@
-use super::error::{JuntaError, JuntaErrorKind};
+use super::address::Address;
+use super::close::CloseCode;
+use super::codec::{Codec, Frame};
+use super::delivery::DeliveryFuture;
+use super::error::{JuntaError, JuntaErrorKind, JuntaResult};
+use super::idle::{Idle, IdleTimer};
+use super::metrics::Metrics;
+use super::outbox::{Outbox, OutboxReader, SendFuture, SendStatus, SlowConsumer};
 use super::server::{Broadcast, MessageContent};
+use super::stream::{BinaryStream, StreamFuture, TakeStream, Upload};
 use atomic_counter::AtomicCounter;
+use bytes::Bytes;
 use futures::prelude::*;
 use futures::sink::Sink;
 use futures::stream::{SplitSink, SplitStream};
-use futures::sync::mpsc::{Receiver, Sender};
-use futures::sync::oneshot::{Receiver as OneReceiver, Sender as OneSender};
-use junta_service::error::ServiceError;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use futures::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 use tokio::codec::Framed;
-use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
+use typemap::ShareMap;
 use uuid::Uuid;
-use websocket::r#async::MessageCodec;
 use websocket::{CloseData, OwnedMessage};
 
-macro_rules! poll_stream {
-    ($stream: expr, $sender: expr) => {
-        match $stream.poll() {
-            Ok(Async::Ready(None)) => {
-                return Ok(Async::Ready(()));
-            }
-            Ok(Async::Ready(Some(msg))) => {
-                //println!("got some {:?}", msg);
-                $sender.start_send(msg); //.unwrap();
-                                         // $sender = $sender.clone().send(msg).wait();
-
-                Some(())
-            }
-            Ok(Async::NotReady) => None,
-            Err(e) => return Err(JuntaErrorKind::Service(ServiceError::ReceiverClosed).into()),
-        }
-    };
-}
-
 #[derive(Clone, PartialEq, Debug)]
 pub enum ClientEvent {
     Connect,
     Message(MessageContent),
+    /// A large binary message, see `ServerBuilder::stream_binary`.
+    Stream(TakeStream),
     Close(Option<CloseData>),
 }
 
@@ -57,12 +47,27 @@ impl ClientEvent {
         }
     }
 
+    pub fn is_stream(&self) -> bool {
+        match self {
+            ClientEvent::Stream(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn is_close(&self) -> bool {
         match self {
             ClientEvent::Close(_) => true,
             _ => false,
         }
     }
+
+    /// The code of a close event, `None` for other events.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            ClientEvent::Close(data) => Some(CloseCode::of(data.as_ref())),
+            _ => None,
+        }
+    }
 }
 
 // impl ClientEvent {
@@ -76,21 +81,29 @@ impl ClientEvent {
 //     }
 // }
 
+/// The close reason reported when a client misses its heartbeat.
+pub const HEARTBEAT_TIMEOUT: &str = "HEARTBEAT_TIMEOUT";
+
 //#[derive(Clone)]
 pub struct Client {
     pub(crate) id: Uuid,
-    pub(crate) sender: Sender<OwnedMessage>,
+    pub(crate) outbox: Arc<Outbox>,
+    pub(crate) slow_consumer: Mutex<SlowConsumer>,
     pub(crate) server: Arc<
         Broadcast<Future = futures::future::FutureResult<(), JuntaError>> + Send + Sync + 'static,
     >,
-    pub(crate) address: SocketAddr,
+    pub(crate) address: Address,
     pub(crate) counter: Arc<atomic_counter::RelaxedCounter>,
+    pub(crate) protocol: Option<String>,
+    pub(crate) upgrade_data: ShareMap,
+    pub(crate) session: RwLock<ShareMap>,
     pub(crate) logger: slog::Logger,
-    pub(crate) close: Mutex<Option<OneSender<()>>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) idle: Arc<Idle>,
 }
 
 impl Client {
-    pub fn send(&self, msg: MessageContent) -> impl Future<Item = (), Error = JuntaError> {
+    pub fn send(&self, msg: MessageContent) -> impl Future<Item = SendStatus, Error = JuntaError> {
         debug!(self.logger, "sending message {:?}", msg);
 
         // self.sender
@@ -99,49 +112,99 @@ impl Client {
         //     .map(|_| ())
         //     .map_err(|_| JuntaErrorKind::Send.into())
 
-        match self.sender.clone().start_send(msg.to_message()) {
-            Ok(_) => future::ok(()),
-            Err(e) => future::err(JuntaErrorKind::Error(Box::new(e)).into()),
-        }
+        let (binary, bytes) = match &msg {
+            MessageContent::Text(text) => (false, text.len()),
+            MessageContent::Binary(data) => (true, data.len()),
+        };
+        self.queue(msg.to_message().into(), binary, bytes)
     }
 
-    pub fn close(&self) -> impl Future<Item = (), Error = JuntaError> {
-        let fut = if self.close.lock().unwrap().is_none() {
-            futures::future::ok(())
-        } else {
-            let sender = self.close.lock().unwrap().take().unwrap();
-            if self.sender.is_closed() {
-                futures::future::ok(())
-            } else {
-                match self
-                    .sender
-                    .clone()
-                    .start_send(OwnedMessage::Close(Some(CloseData::new(
-                        1000,
-                        "NORMAL".to_string(),
-                    )))) {
-                    Ok(_) => future::ok(()),
-                    Err(e) => future::err(JuntaErrorKind::Error(Box::new(e)).into()),
-                }
+    /// Like `send`, but shares `data` with other clients instead of copying it.
+    pub(crate) fn send_shared(
+        &self,
+        binary: bool,
+        data: Bytes,
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
+        let bytes = data.len();
+        self.queue(Frame::Shared { binary, data }, binary, bytes)
+    }
+
+    fn queue(
+        &self,
+        frame: Frame,
+        binary: bool,
+        bytes: usize,
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
+        let metrics = self.metrics.clone();
+        SendFuture::new(
+            self.outbox.clone(),
+            frame,
+            *self.slow_consumer.lock().unwrap(),
+        )
+        .map(move |status| {
+            if let SendStatus::Queued | SendStatus::DroppedOldest = status {
+                metrics.sent(binary, bytes);
             }
+            status
+        })
+    }
 
-            // self.sender
-            //     .clone()
-            //     .send(OwnedMessage::Close(Some(CloseData::new(
-            //         1000,
-            //         "NORMAL".to_string(),
-            //     ))))
-            //     .map(|_| {
-            //         sender.send(()).unwrap();
-            //         ()
-            //     })
-            //     .map_err(|_| JuntaErrorKind::Send.into()),
-
-            // sender.send(());
-            // OneOfTwo::Future2(futures::future::ok(()))
-        };
+    /// Send the chunks of `stream` as one fragmented binary message, so
+    /// it never has to be in memory whole. Until the stream ends, other messages
+    /// to this client are handled as if its queue was full, see `SlowConsumer`.
+    /// Resolves to the size of the message.
+    pub fn send_stream<S>(&self, stream: S) -> impl Future<Item = usize, Error = JuntaError>
+    where
+        S: Stream<Item = Vec<u8>, Error = JuntaError>,
+    {
+        let metrics = self.metrics.clone();
+        StreamFuture::new(self.outbox.clone(), stream).map(move |bytes| {
+            metrics.sent(true, bytes);
+            bytes
+        })
+    }
 
-        fut
+    pub fn close(&self) -> impl Future<Item = (), Error = JuntaError> {
+        self.close_with(CloseCode::Normal, "NORMAL")
+    }
+
+    /// Close the connection with a custom close code and reason.
+    /// Fails if the code can not be sent, see `CloseCode::is_sendable`.
+    pub fn close_with<S: Into<String>>(
+        &self,
+        code: CloseCode,
+        reason: S,
+    ) -> impl Future<Item = (), Error = JuntaError> {
+        if !code.is_sendable() {
+            return future::err(JuntaErrorKind::InvalidCloseCode(code).into());
+        }
+        future::result(self.send_close(code.with_reason(reason)))
+    }
+
+    /// Sends a close frame and stops the connection once the peer answers it,
+    /// or the close grace period passes. Does nothing if the client is already closing.
+    pub(crate) fn send_close(&self, data: CloseData) -> JuntaResult<()> {
+        self.outbox.close(OwnedMessage::Close(Some(data)));
+        Ok(())
+    }
+
+    /// Set what happens when this client's outbound queue is full.
+    pub fn set_slow_consumer(&self, policy: SlowConsumer) {
+        *self.slow_consumer.lock().unwrap() = policy;
+    }
+
+    pub fn slow_consumer(&self) -> SlowConsumer {
+        *self.slow_consumer.lock().unwrap()
+    }
+
+    /// Close this client once it has sent no message for `timeout`,
+    /// counting from its last message. `None` keeps it open.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.idle.set(timeout);
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle.get()
     }
 
     pub fn id(&self) -> &Uuid {
@@ -156,6 +219,62 @@ impl Client {
         Box::new(self.server.broadcast(self, msg))
     }
 
+    /// Like `broadcast`, but resolves to a report of which clients
+    /// got the message once it is queued everywhere, or `timeout` passes.
+    pub fn broadcast_report(
+        &self,
+        msg: MessageContent,
+        timeout: Option<Duration>,
+    ) -> DeliveryFuture {
+        debug!(self.logger, "broadcast message");
+        self.server.broadcast_report(self, msg, timeout)
+    }
+
+    /// Send to every client `predicate` returns true for,
+    /// including this one.
+    pub fn send_where<F>(
+        &self,
+        predicate: F,
+        msg: MessageContent,
+    ) -> Box<Future<Item = (), Error = JuntaError> + Send + 'static>
+    where
+        F: Fn(&Client) -> bool,
+    {
+        Box::new(self.server.send_where(&predicate, msg))
+    }
+
+    /// Send to the clients with the given ids.
+    pub fn send_to(
+        &self,
+        ids: &[Uuid],
+        msg: MessageContent,
+    ) -> Box<Future<Item = (), Error = JuntaError> + Send + 'static> {
+        Box::new(self.server.send_to(ids, msg))
+    }
+
+    pub fn join(&self, room: &str) {
+        self.server.join(room, self)
+    }
+
+    pub fn leave(&self, room: &str) {
+        self.server.leave(room, self)
+    }
+
+    /// The rooms this client is a member of.
+    pub fn rooms(&self) -> Vec<String> {
+        self.server.rooms(self)
+    }
+
+    /// Send to every other member of `room`.
+    pub fn broadcast_room(
+        &self,
+        room: &str,
+        msg: MessageContent,
+    ) -> Box<Future<Item = (), Error = JuntaError> + Send + 'static> {
+        debug!(self.logger, "broadcast message to room"; "room" => room);
+        Box::new(self.server.send_to_room(room, msg, Some(self)))
+    }
+
     pub fn next_seq(&self) -> usize {
         self.counter.inc()
     }
@@ -164,9 +283,33 @@ impl Client {
         &self.logger
     }
 
-    pub fn address(&self) -> &SocketAddr {
+    /// The metrics of the server this client is connected to.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn address(&self) -> &Address {
         &self.address
     }
+
+    /// The subprotocol negotiated during the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|p| p.as_str())
+    }
+
+    /// Data attached by `ServerBuilder::on_upgrade`.
+    pub fn upgrade_data(&self) -> &ShareMap {
+        &self.upgrade_data
+    }
+
+    /// Data kept between events for as long as the client is connected.
+    pub fn session(&self) -> RwLockReadGuard<ShareMap> {
+        self.session.read().unwrap()
+    }
+
+    pub fn session_mut(&self) -> RwLockWriteGuard<ShareMap> {
+        self.session.write().unwrap()
+    }
 }
 
 impl std::fmt::Debug for Client {
@@ -187,59 +330,283 @@ impl PartialEq for Client {
 //     }
 // }
 
-pub struct ClientFuture {
+/// What a connection hands to its dispatcher.
+pub(crate) enum Incoming {
+    Message(OwnedMessage),
+    Stream(BinaryStream),
+}
+
+pub struct ClientFuture<S> {
     //id: Uuid,
-    sink: SplitSink<Framed<TcpStream, MessageCodec<OwnedMessage>>>,
-    stream: SplitStream<Framed<TcpStream, MessageCodec<OwnedMessage>>>,
-    sender: Sender<OwnedMessage>,
-    recv: Receiver<OwnedMessage>,
-    close: OneReceiver<()>,
-    closed: bool,
+    sink: SplitSink<Framed<S, Codec>>,
+    stream: SplitStream<Framed<S, Codec>>,
+    sender: Sender<Incoming>,
+    recv: OutboxReader,
+    pending: Option<Frame>,
+    received: Option<Incoming>,
+    upload: Option<Upload>,
+    chunk: Option<(Vec<u8>, bool)>,
+    /// Size of the streamed message so far
+    streamed: usize,
+    metrics: Option<Arc<Metrics>>,
+    reading: bool,
+    peer_closed: bool,
+    heartbeat: Option<Heartbeat>,
+    idle: Option<IdleTimer>,
+    grace: Duration,
+    closing: Option<Delay>,
 }
 
-impl ClientFuture {
-    pub fn new(
+struct Heartbeat {
+    interval: Interval,
+    timeout: Duration,
+    last_seen: Instant,
+}
+
+impl<S> ClientFuture<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub(crate) fn new(
         //id: uuid::Uuid,
-        sink: SplitSink<Framed<TcpStream, MessageCodec<OwnedMessage>>>,
-        stream: SplitStream<Framed<TcpStream, MessageCodec<OwnedMessage>>>,
-        sender: Sender<OwnedMessage>,
-        recv: Receiver<OwnedMessage>,
-        close: OneReceiver<()>,
-    ) -> ClientFuture {
+        sink: SplitSink<Framed<S, Codec>>,
+        stream: SplitStream<Framed<S, Codec>>,
+        sender: Sender<Incoming>,
+        recv: OutboxReader,
+    ) -> ClientFuture<S> {
         ClientFuture {
             //id,
             sink,
             stream,
             sender,
             recv,
-            close,
-            closed: false,
+            pending: None,
+            received: None,
+            upload: None,
+            chunk: None,
+            streamed: 0,
+            metrics: None,
+            reading: true,
+            peer_closed: false,
+            heartbeat: None,
+            idle: None,
+            grace: Duration::from_secs(0),
+            closing: None,
         }
     }
-}
 
-impl Future for ClientFuture {
-    type Item = ();
-    type Error = JuntaError;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.closed {
-            return Ok(Async::Ready(()));
+    /// Ping the peer every `interval`, and give up on it
+    /// if nothing has been received for `timeout`.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some(Heartbeat {
+            interval: Interval::new_interval(interval),
+            timeout,
+            last_seen: Instant::now(),
+        });
+        self
+    }
+
+    /// Close the connection with `code` once the client's idle timeout passes.
+    pub(crate) fn idle_timeout(mut self, idle: Arc<Idle>, code: CloseCode) -> Self {
+        self.idle = Some(IdleTimer::new(idle, code));
+        self
+    }
+
+    /// Count streamed messages in `metrics` once they are complete.
+    pub(crate) fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// How long to wait for the peer to answer our close frame.
+    pub fn close_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    fn seen(&mut self) {
+        if let Some(heartbeat) = &mut self.heartbeat {
+            heartbeat.last_seen = Instant::now();
         }
-        match poll_stream!(self.recv, self.sink) {
-            Some(_) => {
-                poll_stream!(self.recv, self.sink);
-                ()
+    }
+
+    /// Moves queued messages into the sink, as long as it has room.
+    /// Returns true once the outbox is closed and drained.
+    fn poll_outgoing(&mut self) -> Result<bool, JuntaError> {
+        loop {
+            if let Some(msg) = self.pending.take() {
+                if let AsyncSink::NotReady(msg) = self.sink.start_send(msg)? {
+                    self.pending = Some(msg);
+                    return Ok(false);
+                }
             }
-            None => (),
-        };
+            match self.recv.poll() {
+                Ok(Async::Ready(Some(msg))) => self.pending = Some(msg),
+                Ok(Async::Ready(None)) => return Ok(true),
+                Ok(Async::NotReady) | Err(_) => return Ok(false),
+            }
+        }
+    }
 
-        match poll_stream!(self.stream, self.sender) {
-            Some(_) => {
-                poll_stream!(self.stream, self.sender);
-                ()
+    /// Moves received frames to the dispatcher, as long as it keeps up.
+    /// Returns true once the peer has closed the stream.
+    fn poll_incoming(&mut self) -> Result<bool, JuntaError> {
+        loop {
+            if let Some(msg) = self.received.take() {
+                let ret = self
+                    .sender
+                    .start_send(msg)
+                    .map_err(|e| JuntaError::from(JuntaErrorKind::Error(Box::new(e))))?;
+                if let AsyncSink::NotReady(msg) = ret {
+                    self.received = Some(msg);
+                    return Ok(false);
+                }
+            }
+            if let Some((data, finished)) = self.chunk.take() {
+                if let Some(upload) = &mut self.upload {
+                    match upload.start_send(data, finished) {
+                        Ok(AsyncSink::NotReady(data)) => {
+                            self.chunk = Some((data, finished));
+                            return Ok(false);
+                        }
+                        Ok(AsyncSink::Ready) => {}
+                        // The handler dropped the stream, skip the rest of the message
+                        Err(()) => self.upload = None,
+                    }
+                }
+                if finished {
+                    self.upload = None;
+                }
             }
-            None => (),
+            if !self.reading {
+                return Ok(false);
+            }
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    self.seen();
+                    let data = match &frame {
+                        Frame::Message(msg) => msg.is_data(),
+                        _ => true,
+                    };
+                    if data {
+                        if let Some(idle) = &mut self.idle {
+                            idle.active();
+                        }
+                    }
+                    match frame {
+                        Frame::Message(msg) => {
+                            if msg.is_close() {
+                                self.peer_closed = true;
+                            }
+                            self.received = Some(Incoming::Message(msg));
+                        }
+                        Frame::Chunk {
+                            data,
+                            first,
+                            finished,
+                        } => {
+                            if first {
+                                let (upload, stream) = Upload::new();
+                                self.upload = Some(upload);
+                                self.received = Some(Incoming::Stream(stream));
+                                self.streamed = 0;
+                            }
+                            self.streamed += data.len();
+                            if finished {
+                                if let Some(metrics) = &self.metrics {
+                                    metrics.received_stream(self.streamed);
+                                }
+                            }
+                            self.chunk = Some((data, finished));
+                        }
+                        // Never read, only written
+                        Frame::Shared { .. } => {}
+                    }
+                }
+                Ok(Async::Ready(None)) => return Ok(true),
+                Ok(Async::NotReady) => return Ok(false),
+                // Stop reading and let the close frame go out
+                Err(e) => match e.kind() {
+                    JuntaErrorKind::LimitExceeded(reason) => {
+                        self.recv.disconnect(reason.clone());
+                        self.reading = false;
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Returns true once the close handshake is done, or the peer
+    /// has not answered our close frame within the grace period.
+    fn poll_grace(&mut self) -> Result<bool, JuntaError> {
+        if self.peer_closed || !self.reading {
+            return Ok(true);
+        }
+        let grace = self.grace;
+        let delay = self
+            .closing
+            .get_or_insert_with(|| Delay::new(Instant::now() + grace));
+        let ret = delay
+            .poll()
+            .map_err(|e| JuntaError::from(JuntaErrorKind::Error(Box::new(e))))?;
+        Ok(ret.is_ready())
+    }
+
+    /// Closes the connection once the client has been idle too long.
+    fn poll_idle(&mut self) -> Result<(), JuntaError> {
+        let ret = match &mut self.idle {
+            Some(idle) => idle.poll()?,
+            None => return Ok(()),
         };
+        if let Async::Ready(reason) = ret {
+            self.recv.disconnect(reason);
+            self.idle = None;
+        }
+        Ok(())
+    }
+
+    /// Sends due pings and returns true if the peer timed out.
+    fn poll_heartbeat(&mut self) -> Result<bool, JuntaError> {
+        let heartbeat = match &mut self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(false),
+        };
+        while let Async::Ready(Some(_)) = heartbeat
+            .interval
+            .poll()
+            .map_err(|e| JuntaError::from(JuntaErrorKind::Error(Box::new(e))))?
+        {
+            if heartbeat.last_seen.elapsed() > heartbeat.timeout {
+                return Ok(true);
+            }
+            // Fails once the outbox is closed, when a ping no longer matters
+            let _ = self.recv.push_control(OwnedMessage::Ping(Vec::new()));
+        }
+        Ok(false)
+    }
+}
+
+impl<S> Future for ClientFuture<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Why the connection ended, if not by the peer closing it.
+    type Item = Option<CloseData>;
+    type Error = JuntaError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_idle()?;
+        let drained = self.poll_outgoing()?;
+
+        if self.poll_incoming()? {
+            return Ok(Async::Ready(None));
+        }
+
+        if self.poll_heartbeat()? {
+            return Ok(Async::Ready(Some(
+                CloseCode::Abnormal.with_reason(HEARTBEAT_TIMEOUT),
+            )));
+        }
 
         match self.sender.poll_complete() {
             Ok(Async::NotReady) => (),
@@ -258,29 +625,12 @@ impl Future for ClientFuture {
         match self.sink.poll_complete() {
             Ok(Async::NotReady) => (),
             Ok(Async::Ready(_)) => {
-                self.sink.poll_complete();
-
-                //return Ok(Async::Ready(()));
+                if drained && self.poll_grace()? {
+                    return Ok(Async::Ready(self.recv.reason()));
+                }
                 ()
             }
-            Err(e) => return Err(JuntaErrorKind::Transport(e).into()),
-        };
-
-        match self.close.poll() {
-            Ok(Async::NotReady) => (),
-            Ok(Async::Ready(_)) => {
-                //println!("closing the channel");
-                self.sink.close();
-                self.closed = true;
-            }
-            Err(e) => {
-                //self.sink.close();
-                //<self.sink.close();
-
-                //println!("cerror polling close");
-                return Ok(Async::Ready(()));
-                //return Err(JuntaErrorKind::Error(Box::new(e)).into());
-            }
+            Err(e) => return Err(e),
         };
 
         Ok(Async::NotReady)
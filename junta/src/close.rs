@@ -0,0 +1,175 @@
+use std::fmt;
+use websocket::CloseData;
+
+/// A websocket close code, as defined by RFC 6455 and the IANA registry.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CloseCode {
+    /// 1000, the purpose of the connection is fulfilled.
+    Normal,
+    /// 1001, the server is shutting down or the browser left the page.
+    GoingAway,
+    /// 1002
+    ProtocolError,
+    /// 1003, eg. binary data to a text only endpoint.
+    Unsupported,
+    /// 1005, the close frame had no code. Never sent.
+    NoStatus,
+    /// 1006, the connection was lost without a close frame. Never sent.
+    Abnormal,
+    /// 1007, eg. invalid utf-8 in a text message.
+    InvalidPayload,
+    /// 1008
+    PolicyViolation,
+    /// 1009
+    MessageTooBig,
+    /// 1010, the client wanted an extension the server did not offer.
+    MandatoryExtension,
+    /// 1011
+    InternalError,
+    /// 1012
+    ServiceRestart,
+    /// 1013
+    TryAgainLater,
+    /// 1014
+    BadGateway,
+    /// 1015, the TLS handshake failed. Never sent.
+    TlsHandshake,
+    /// 4000 to 4999, free for applications to use.
+    Application(u16),
+    /// Any other code.
+    Other(u16),
+}
+
+impl CloseCode {
+    /// The code of a close frame, `NoStatus` if it had none.
+    pub fn of(data: Option<&CloseData>) -> CloseCode {
+        data.map_or(CloseCode::NoStatus, |data| data.status_code.into())
+    }
+
+    pub fn is_application(self) -> bool {
+        match self {
+            CloseCode::Application(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether an endpoint may send this code in a close frame. Codes only
+    /// reported locally, like `Abnormal`, and unassigned codes are not.
+    pub fn is_sendable(self) -> bool {
+        match self {
+            CloseCode::NoStatus | CloseCode::Abnormal | CloseCode::TlsHandshake => false,
+            CloseCode::Application(code) => (4000..=4999).contains(&code),
+            // Registered codes may also be given as `Other`, eg. when echoing a peer
+            CloseCode::Other(code) => match CloseCode::from(code) {
+                CloseCode::Other(_) => (3000..=4999).contains(&code),
+                named => named.is_sendable(),
+            },
+            _ => true,
+        }
+    }
+
+    /// A close frame with this code and `reason`.
+    pub fn with_reason<S: Into<String>>(self, reason: S) -> CloseData {
+        CloseData::new(self.into(), reason.into())
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshake,
+            4000..=4999 => CloseCode::Application(code),
+            _ => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Application(code) | CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", u16::from(*self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_close_with() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let loopback = Server::loopback().serve_loopback(
+            runtime.executor(),
+            service_fn(|_ctx: Context<ClientEvent>| Ok(())),
+        );
+        let (_, client) = loopback.connect();
+        let client = client.client().clone();
+
+        for code in vec![
+            CloseCode::NoStatus,
+            CloseCode::Abnormal,
+            CloseCode::TlsHandshake,
+            CloseCode::Other(1004),
+            CloseCode::Other(2000),
+            CloseCode::Other(5000),
+            CloseCode::Application(1000),
+            CloseCode::Application(5000),
+        ] {
+            match runtime.block_on(client.close_with(code, "BYE")) {
+                Err(e) => match e.kind() {
+                    JuntaErrorKind::InvalidCloseCode(c) => assert_eq!(*c, code),
+                    _ => panic!("unexpected error {}", e),
+                },
+                Ok(_) => panic!("{} was sent", code),
+            }
+        }
+        for code in vec![
+            CloseCode::Other(1000),
+            CloseCode::Other(1008),
+            CloseCode::Other(3000),
+            CloseCode::Application(4000),
+        ] {
+            assert!(code.is_sendable(), "{} is not sendable", code);
+        }
+        runtime
+            .block_on(client.close_with(CloseCode::Other(1008), "BYE"))
+            .unwrap();
+    }
+}
@@ -0,0 +1,495 @@
+use super::deflate::DeflateContext;
+use super::error::{JuntaError, JuntaErrorKind};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io::Cursor;
+use std::mem;
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Encoder, Framed, FramedParts};
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::r#async::codec::ws::DataFrameCodec;
+use websocket::r#async::{MessageCodec, MsgCodecCtx};
+use websocket::ws::dataframe::DataFrame as DataFrameTrait;
+use websocket::ws::util::header::{read_header, write_header, DataFrameFlags, DataFrameHeader};
+use websocket::ws::util::mask::{gen_mask, mask_data};
+use websocket::ws::Message;
+use websocket::{CloseData, OwnedMessage, WebSocketError};
+
+/// The close reason sent when a frame or message is too large.
+pub const MESSAGE_TOO_BIG: &str = "MESSAGE_TOO_BIG";
+/// The close reason sent when a client sends messages too fast.
+pub const TOO_MANY_MESSAGES: &str = "TOO_MANY_MESSAGES";
+/// The close reason sent when a frame sets a reserved bit
+/// no negotiated extension gives a meaning.
+pub const RESERVED_BITS: &str = "RESERVED_BITS";
+
+/// Caps on what a single client may send.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct MessageLimits {
+    pub(crate) max_frame_size: Option<usize>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_messages_per_second: Option<usize>,
+}
+
+/// What `Codec` reads and writes: whole messages,
+/// or the pieces of a binary message which is streamed.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum Frame {
+    Message(OwnedMessage),
+    /// Part of a binary message, `first` starts it and `finished` ends it.
+    Chunk {
+        data: Vec<u8>,
+        first: bool,
+        finished: bool,
+    },
+    /// A whole text or binary message sent to many clients,
+    /// whose payload is shared instead of copied. Only written.
+    Shared {
+        binary: bool,
+        data: Bytes,
+    },
+}
+
+impl From<OwnedMessage> for Frame {
+    fn from(msg: OwnedMessage) -> Frame {
+        Frame::Message(msg)
+    }
+}
+
+/// Like `MessageCodec`, but refuses frames and messages over `MessageLimits`
+/// before buffering them, and compresses messages if permessage-deflate
+/// was agreed on. Binary messages over the stream threshold
+/// are read as `Frame::Chunk`s instead of being buffered.
+pub(crate) struct Codec {
+    frames: DataFrameCodec<DataFrame>,
+    messages: MessageCodec<OwnedMessage>,
+    buffer: Vec<DataFrame>,
+    buffered: usize,
+    limits: MessageLimits,
+    window: Instant,
+    received: usize,
+    deflate: Option<DeflateContext>,
+    masked: bool,
+    stream_threshold: Option<usize>,
+    streaming: bool,
+}
+
+impl Codec {
+    pub(crate) fn new(context: MsgCodecCtx, limits: MessageLimits) -> Codec {
+        Codec {
+            frames: DataFrameCodec::new(context),
+            messages: MessageCodec::new(context),
+            buffer: Vec::new(),
+            buffered: 0,
+            limits,
+            window: Instant::now(),
+            received: 0,
+            deflate: None,
+            masked: context == MsgCodecCtx::Client,
+            stream_threshold: None,
+            streaming: false,
+        }
+    }
+
+    /// Stream binary messages once more than `threshold` bytes are buffered.
+    pub(crate) fn stream_binary(mut self, threshold: Option<usize>) -> Self {
+        self.stream_threshold = threshold;
+        self
+    }
+
+    pub(crate) fn deflate(mut self, deflate: Option<DeflateContext>) -> Self {
+        self.deflate = deflate;
+        self
+    }
+
+    /// Moves an upgraded connection over to this codec.
+    pub(crate) fn wrap<S>(
+        framed: Framed<S, MessageCodec<OwnedMessage>>,
+        codec: Codec,
+    ) -> Framed<S, Codec> {
+        let old = framed.into_parts();
+        let mut parts = FramedParts::new(old.io, codec);
+        parts.read_buf = old.read_buf;
+        parts.write_buf = old.write_buf;
+        Framed::from_parts(parts)
+    }
+
+    fn exceeds(limit: Option<usize>, size: u64) -> bool {
+        limit.map_or(false, |max| size > max as u64)
+    }
+
+    /// Counts a message against the per second limit.
+    fn tick(&mut self) -> bool {
+        if self.window.elapsed() >= Duration::from_secs(1) {
+            self.window = Instant::now();
+            self.received = 0;
+        }
+        self.received += 1;
+        Codec::exceeds(self.limits.max_messages_per_second, self.received as u64)
+    }
+
+    /// Ends the current message.
+    fn finish(&mut self) -> Result<(), JuntaError> {
+        self.buffered = 0;
+        self.streaming = false;
+        if self.tick() {
+            return Err(violation(1008, TOO_MANY_MESSAGES));
+        }
+        Ok(())
+    }
+
+    /// Whether to stop buffering the current message. Compressed
+    /// messages are always buffered, as they are inflated whole.
+    fn should_stream(&self) -> bool {
+        let frame = &self.buffer[0];
+        frame.opcode == Opcode::Binary
+            && !frame.reserved[0]
+            && Codec::exceeds(self.stream_threshold, self.buffered as u64)
+    }
+}
+
+fn violation(code: u16, reason: &str) -> JuntaError {
+    JuntaErrorKind::LimitExceeded(CloseData::new(code, reason.to_string())).into()
+}
+
+fn inflate(
+    deflate: &mut DeflateContext,
+    frames: Vec<DataFrame>,
+    limit: Option<usize>,
+) -> Result<OwnedMessage, JuntaError> {
+    let opcode = frames[0].opcode;
+    let data = frames.into_iter().flat_map(|frame| frame.data).collect();
+    let data = deflate
+        .decompress(data, limit)?
+        .ok_or_else(|| violation(1009, MESSAGE_TOO_BIG))?;
+    match opcode {
+        Opcode::Text => String::from_utf8(data)
+            .map(OwnedMessage::Text)
+            .map_err(|_| WebSocketError::ProtocolError("Invalid UTF-8 in text message").into()),
+        _ => Ok(OwnedMessage::Binary(data)),
+    }
+}
+
+/// Writes a whole message straight from `data`, which may be shared.
+fn write_shared(
+    masked: bool,
+    opcode: Opcode,
+    data: &[u8],
+    dst: &mut BytesMut,
+) -> Result<(), JuntaError> {
+    let mask = if masked { Some(gen_mask()) } else { None };
+    let header = DataFrameHeader {
+        flags: DataFrameFlags::FIN,
+        opcode: opcode as u8,
+        mask,
+        len: data.len() as u64,
+    };
+    // At most 14 bytes of header
+    dst.reserve(14 + data.len());
+    write_header(&mut dst.writer(), header)?;
+    match mask {
+        Some(mask) => dst.put_slice(&mask_data(mask, data)),
+        None => dst.put_slice(data),
+    }
+    Ok(())
+}
+
+fn write_deflated(
+    deflate: &mut DeflateContext,
+    masked: bool,
+    opcode: Opcode,
+    data: &[u8],
+    dst: &mut BytesMut,
+) -> Result<(), JuntaError> {
+    let mut frame = DataFrame::new(true, opcode, deflate.compress(data)?);
+    frame.reserved[0] = true;
+    dst.reserve(frame.frame_size(masked));
+    Ok(frame.write_to(&mut dst.writer(), masked)?)
+}
+
+impl Decoder for Codec {
+    type Item = Frame;
+    type Error = JuntaError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            // Check the size in the header before waiting for the payload
+            let header = match read_header(&mut Cursor::new(src.as_ref())) {
+                Ok(header) => header,
+                Err(WebSocketError::NoDataAvailable) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if Codec::exceeds(self.limits.max_frame_size, header.len)
+                || (header.opcode < 8
+                    && Codec::exceeds(
+                        self.limits.max_message_size,
+                        self.buffered as u64 + header.len,
+                    ))
+            {
+                return Err(violation(1009, MESSAGE_TOO_BIG));
+            }
+
+            let frame = match self.frames.decode(src)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            // Only RSV1 has a meaning, and only with permessage-deflate
+            if (frame.reserved[0] && self.deflate.is_none())
+                || frame.reserved[1]
+                || frame.reserved[2]
+            {
+                return Err(violation(1002, RESERVED_BITS));
+            }
+            let is_first = self.buffer.is_empty() && !self.streaming;
+            let finished = frame.finished;
+
+            match frame.opcode as u8 {
+                0 if is_first => {
+                    return Err(WebSocketError::ProtocolError(
+                        "Unexpected continuation data frame opcode",
+                    )
+                    .into());
+                }
+                8..=15 => {
+                    // Control frames count too, so pings can not be used to flood
+                    if self.tick() {
+                        return Err(violation(1008, TOO_MANY_MESSAGES));
+                    }
+                    return Ok(Some(Frame::Message(OwnedMessage::from_dataframes(vec![
+                        frame,
+                    ])?)));
+                }
+                1..=7 if !is_first => {
+                    return Err(
+                        WebSocketError::ProtocolError("Unexpected data frame opcode").into(),
+                    );
+                }
+                _ if self.streaming => {
+                    self.buffered += frame.data.len();
+                    if finished {
+                        self.finish()?;
+                    }
+                    return Ok(Some(Frame::Chunk {
+                        data: frame.data,
+                        first: false,
+                        finished,
+                    }));
+                }
+                _ => {
+                    self.buffered += frame.data.len();
+                    self.buffer.push(frame);
+                }
+            }
+
+            if self.should_stream() {
+                let buffer = mem::replace(&mut self.buffer, Vec::new());
+                let data = buffer.into_iter().flat_map(|frame| frame.data).collect();
+                if finished {
+                    self.finish()?;
+                } else {
+                    self.streaming = true;
+                }
+                return Ok(Some(Frame::Chunk {
+                    data,
+                    first: true,
+                    finished,
+                }));
+            }
+
+            if finished {
+                self.finish()?;
+                let buffer = mem::replace(&mut self.buffer, Vec::new());
+                let msg = match &mut self.deflate {
+                    Some(deflate) if buffer[0].reserved[0] => {
+                        inflate(deflate, buffer, self.limits.max_message_size)?
+                    }
+                    _ => OwnedMessage::from_dataframes(buffer)?,
+                };
+                return Ok(Some(Frame::Message(msg)));
+            }
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Frame;
+    type Error = JuntaError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let item = match item {
+            Frame::Message(msg) => msg,
+            Frame::Chunk {
+                data,
+                first,
+                finished,
+            } => {
+                let opcode = if first {
+                    Opcode::Binary
+                } else {
+                    Opcode::Continuation
+                };
+                let frame = DataFrame::new(finished, opcode, data);
+                dst.reserve(frame.frame_size(self.masked));
+                return Ok(frame.write_to(&mut dst.writer(), self.masked)?);
+            }
+            Frame::Shared { binary, data } => {
+                let opcode = if binary { Opcode::Binary } else { Opcode::Text };
+                return match &mut self.deflate {
+                    Some(deflate) if deflate.should_compress(&data) => {
+                        write_deflated(deflate, self.masked, opcode, &data, dst)
+                    }
+                    _ => write_shared(self.masked, opcode, &data, dst),
+                };
+            }
+        };
+        match (&mut self.deflate, item) {
+            (Some(deflate), OwnedMessage::Text(ref text))
+                if deflate.should_compress(text.as_bytes()) =>
+            {
+                write_deflated(deflate, self.masked, Opcode::Text, text.as_bytes(), dst)
+            }
+            (Some(deflate), OwnedMessage::Binary(ref data)) if deflate.should_compress(data) => {
+                write_deflated(deflate, self.masked, Opcode::Binary, data, dst)
+            }
+            (_, item) => Ok(self.messages.encode(item, dst)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::Deflate;
+
+    fn encode(msg: OwnedMessage) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageCodec::new(MsgCodecCtx::Client)
+            .encode(msg, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn close(error: JuntaError) -> CloseData {
+        match error.kind() {
+            JuntaErrorKind::LimitExceeded(close) => close.clone(),
+            _ => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn test_message_limits() {
+        let limits = MessageLimits {
+            max_frame_size: Some(8),
+            max_message_size: None,
+            max_messages_per_second: Some(2),
+        };
+
+        // Refused from the header alone
+        let mut codec = Codec::new(MsgCodecCtx::Server, limits);
+        let mut buf = encode(OwnedMessage::Binary(vec![0; 64]));
+        buf.truncate(6);
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            close(error),
+            CloseData::new(1009, MESSAGE_TOO_BIG.to_string())
+        );
+
+        let mut codec = Codec::new(MsgCodecCtx::Server, limits);
+        let mut buf = encode(OwnedMessage::Text("1".to_string()));
+        buf.extend_from_slice(&encode(OwnedMessage::Text("2".to_string())));
+        buf.extend_from_slice(&encode(OwnedMessage::Text("3".to_string())));
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            close(error),
+            CloseData::new(1008, TOO_MANY_MESSAGES.to_string())
+        );
+
+        let mut codec = Codec::new(MsgCodecCtx::Server, limits);
+        let mut buf = encode(OwnedMessage::Ping(Vec::new()));
+        buf.extend_from_slice(&encode(OwnedMessage::Pong(Vec::new())));
+        buf.extend_from_slice(&encode(OwnedMessage::Ping(Vec::new())));
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(
+            close(error),
+            CloseData::new(1008, TOO_MANY_MESSAGES.to_string())
+        );
+    }
+
+    #[test]
+    fn test_deflate_roundtrip() {
+        let deflate = Deflate::new().threshold(32);
+        let mut server = Codec::new(MsgCodecCtx::Server, MessageLimits::default())
+            .deflate(Some(DeflateContext::server(&deflate)));
+        let mut client = Codec::new(MsgCodecCtx::Client, MessageLimits::default())
+            .deflate(Some(DeflateContext::server(&deflate)));
+
+        let large = OwnedMessage::Text("{\"event\":\"update\"}".repeat(8));
+        let small = OwnedMessage::Binary(vec![1, 2, 3]);
+        let mut buf = BytesMut::new();
+        server.encode(large.clone().into(), &mut buf).unwrap();
+        // RSV1 marks the message as compressed
+        assert_eq!(buf[0], 0xc1);
+        server.encode(small.clone().into(), &mut buf).unwrap();
+
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(large.into()));
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(small.into()));
+    }
+
+    #[test]
+    fn test_shared() {
+        let shared = |ctx, binary, data: &[u8]| {
+            let mut buf = BytesMut::new();
+            Codec::new(ctx, MessageLimits::default())
+                .encode(
+                    Frame::Shared {
+                        binary,
+                        data: Bytes::from(data),
+                    },
+                    &mut buf,
+                )
+                .unwrap();
+            buf
+        };
+
+        // Written just like the message it was made from
+        let mut buf = BytesMut::new();
+        Codec::new(MsgCodecCtx::Server, MessageLimits::default())
+            .encode(OwnedMessage::Binary(vec![1, 2, 3]).into(), &mut buf)
+            .unwrap();
+        assert_eq!(shared(MsgCodecCtx::Server, true, &[1, 2, 3]), buf);
+
+        let mut server = Codec::new(MsgCodecCtx::Server, MessageLimits::default());
+        let mut buf = shared(MsgCodecCtx::Client, false, b"Hello");
+        assert_eq!(
+            server.decode(&mut buf).unwrap(),
+            Some(OwnedMessage::Text("Hello".to_string()).into())
+        );
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let deflate = Deflate::new();
+        let frame = |reserved: [bool; 3]| {
+            let mut frame = DataFrame::new(true, Opcode::Text, b"Hello".to_vec());
+            frame.reserved = reserved;
+            let mut buf = BytesMut::new();
+            frame.write_to(&mut (&mut buf).writer(), true).unwrap();
+            buf
+        };
+        let reserved = CloseData::new(1002, RESERVED_BITS.to_string());
+
+        let mut codec = Codec::new(MsgCodecCtx::Server, MessageLimits::default());
+        let error = codec.decode(&mut frame([true, false, false])).unwrap_err();
+        assert_eq!(close(error), reserved);
+
+        for bits in &[[false, true, false], [false, false, true]] {
+            let mut codec = Codec::new(MsgCodecCtx::Server, MessageLimits::default())
+                .deflate(Some(DeflateContext::server(&deflate)));
+            let error = codec.decode(&mut frame(*bits)).unwrap_err();
+            assert_eq!(close(error), reserved);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use super::address::Address;
+use super::client::{Client, ClientEvent};
+use super::codec::{Codec, MessageLimits};
+use super::context::Context;
+use super::error::{JuntaError, JuntaErrorKind, JuntaResult};
+use super::server::{Broadcaster, ClientConfig, Dispatcher};
+use future_ext::{OneOfTwo, OneOfTwoFuture};
+use futures::prelude::*;
+use junta_service::prelude::*;
+use native_tls::TlsConnector;
+use slog::{Discard, Logger};
+use std::sync::Arc;
+use tokio::codec::Framed;
+use tokio::prelude::{AsyncRead, AsyncWrite};
+use tokio::runtime::TaskExecutor;
+use typemap::TypeMap;
+use websocket::header::{Headers, WebSocketProtocol};
+use websocket::r#async::MsgCodecCtx;
+use websocket::url::Url;
+use websocket::ClientBuilder as WSClientBuilder;
+
+pub struct Connector {
+    url: Url,
+    logger: Logger,
+    tls: Option<TlsConnector>,
+    protocols: Vec<String>,
+}
+
+impl Connector {
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// Use a custom tls configuration for `wss://` urls,
+    /// eg. to trust a self-signed certificate.
+    pub fn tls(mut self, connector: TlsConnector) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// Subprotocols to request, in order of preference.
+    /// Defaults to `rust-websocket`.
+    pub fn protocols<S: AsRef<str>>(mut self, protocols: &[S]) -> Self {
+        self.protocols = protocols.iter().map(|p| p.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Connects to the server and feeds the connection into `handler`,
+    /// the same way `ServerBuilder::serve` does for incoming clients.
+    /// Resolves to the client representing the server.
+    pub fn serve<H>(
+        self,
+        executor: TaskExecutor,
+        handler: H,
+    ) -> impl Future<Item = Arc<Client>, Error = JuntaError>
+    where
+        H: IntoService<Input = Context<ClientEvent>, Output = (), Error = JuntaError>,
+        <H as IntoService>::Service: 'static + Send + Sync,
+    {
+        let handler = Arc::new(handler.into_service());
+        let logger = self.logger;
+        let builder = WSClientBuilder::from_url(&self.url).add_protocols(self.protocols);
+
+        let fut = if self.url.scheme() == "wss" {
+            OneOfTwo::First(
+                builder
+                    .async_connect_secure(self.tls)
+                    .map_err(JuntaError::from)
+                    .and_then(move |(framed, headers)| {
+                        let addr = framed.get_ref().get_ref().get_ref().peer_addr()?.into();
+                        let protocol = negotiated(&headers);
+                        let codec = Codec::new(MsgCodecCtx::Client, MessageLimits::default());
+                        let framed = Codec::wrap(framed, codec);
+                        Ok(attach(logger, executor, handler, framed, addr, protocol))
+                    }),
+            )
+        } else {
+            OneOfTwo::Second(
+                builder
+                    .async_connect_insecure()
+                    .map_err(JuntaError::from)
+                    .and_then(move |(framed, headers)| {
+                        let addr = framed.get_ref().peer_addr()?.into();
+                        let protocol = negotiated(&headers);
+                        let codec = Codec::new(MsgCodecCtx::Client, MessageLimits::default());
+                        let framed = Codec::wrap(framed, codec);
+                        Ok(attach(logger, executor, handler, framed, addr, protocol))
+                    }),
+            )
+        };
+
+        OneOfTwoFuture::new(fut)
+    }
+}
+
+pub(crate) fn attach<H, S>(
+    logger: Logger,
+    executor: TaskExecutor,
+    handler: Arc<H>,
+    framed: Framed<S, Codec>,
+    addr: Address,
+    protocol: Option<String>,
+) -> Arc<Client>
+where
+    H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
+        + 'static
+        + Send
+        + Sync,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let dispatcher = Dispatcher {
+        server: Arc::new(Broadcaster::new(executor.clone())),
+        logger,
+        executor,
+        handler,
+        counter: Arc::new(atomic_counter::RelaxedCounter::new(1)),
+        inflight: futures::sync::mpsc::channel(0).0,
+        config: ClientConfig::default(),
+    };
+    dispatcher.connect(framed, addr, protocol, TypeMap::custom(), None)
+}
+
+fn negotiated(headers: &Headers) -> Option<String> {
+    headers
+        .get::<WebSocketProtocol>()
+        .and_then(|p| p.0.first().cloned())
+}
+
+impl Client {
+    /// Connect to a junta server, eg. `ws://127.0.0.1:2794`.
+    pub fn connect<S: AsRef<str>>(url: S) -> JuntaResult<Connector> {
+        let url = Url::parse(url.as_ref()).map_err(|_| JuntaErrorKind::InvalidAddress)?;
+        Ok(Connector {
+            url,
+            logger: Logger::root(Discard, o! {}),
+            tls: None,
+            protocols: vec!["rust-websocket".to_string()],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use futures::sync::mpsc;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_connect() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (closed, on_close) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    let fut: Box<Future<Item = (), Error = JuntaError> + Send> = match ctx.message()
+                    {
+                        ClientEvent::Message(msg) => {
+                            Box::new(ctx.client().send(msg.clone()).map(|_| ()))
+                        }
+                        ClientEvent::Close(data) => {
+                            closed.unbounded_send(data.clone()).unwrap();
+                            Box::new(futures::future::ok(()))
+                        }
+                        _ => Box::new(futures::future::ok(())),
+                    };
+                    fut
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let (sx, rx) = mpsc::unbounded();
+        let client = runtime
+            .block_on(Client::connect(format!("ws://{}", addr)).unwrap().serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    sx.unbounded_send(ctx.message().clone()).unwrap();
+                    Ok(())
+                }),
+            ))
+            .unwrap();
+        assert_eq!(client.address().as_inet(), Some(&addr));
+
+        let msg = MessageContent::Text("Hello".to_string());
+        runtime.block_on(client.send(msg.clone())).unwrap();
+        let (event, rx) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(event, Some(ClientEvent::Connect));
+        let (event, rx) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(event, Some(ClientEvent::Message(msg)));
+
+        runtime.block_on(client.close()).unwrap();
+        let (data, _) = runtime.block_on(on_close.into_future()).ok().unwrap();
+        let data = data.unwrap().unwrap();
+        assert_eq!((data.status_code, data.reason.as_str()), (1000, "NORMAL"));
+        let (event, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert!(event.unwrap().is_close());
+    }
+}
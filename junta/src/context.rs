@@ -2,6 +2,8 @@ use super::client::Client;
 use super::client::ClientEvent;
 #[cfg(feature = "encoding")]
 use super::error::*;
+#[cfg(feature = "encoding")]
+use super::outbox::SendStatus;
 use super::plugins::{Extensible, Pluggable};
 use super::server::MessageContent;
 #[cfg(feature = "encoding")]
@@ -21,7 +23,7 @@ pub struct Context<I> {
 impl<I> Context<I> {
     pub fn new(client: Arc<Client>, message: ClientEvent) -> Context<ClientEvent> {
         let binary = match message {
-            ClientEvent::Message(MessageContent::Binary(_)) => true,
+            ClientEvent::Message(MessageContent::Binary(_)) | ClientEvent::Stream(_) => true,
             _ => false,
         };
 
@@ -64,7 +66,7 @@ impl<I> Context<I> {
     pub fn send<S: serde::Serialize>(
         &self,
         data: &S,
-    ) -> impl Future<Item = (), Error = JuntaError> {
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
         let ret = if self.binary {
             self.encode_binary(data)
         } else {
@@ -165,7 +167,7 @@ impl<'a, I, M> BorrowedContext<'a, I, M> {
     pub fn send<S: serde::Serialize>(
         &self,
         data: &S,
-    ) -> impl Future<Item = (), Error = JuntaError> {
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
         self.ctx.send(data)
     }
 
@@ -231,7 +233,7 @@ impl<I, M> ChildContext<I, M> {
     pub fn send<S: serde::Serialize>(
         &self,
         data: &S,
-    ) -> impl Future<Item = (), Error = JuntaError> {
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
         self.ctx.send(data)
     }
 
@@ -0,0 +1,269 @@
+use super::error::{JuntaError, JuntaErrorKind};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+/// The `Sec-WebSocket-Extensions` header.
+pub(crate) const EXTENSIONS: &str = "Sec-WebSocket-Extensions";
+
+/// Every compressed message ends with this, which is left out on the wire.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Settings for the permessage-deflate extension (RFC 7692).
+#[derive(Clone, Copy, Debug)]
+pub struct Deflate {
+    server_max_window_bits: u8,
+    client_max_window_bits: u8,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    threshold: usize,
+}
+
+impl Default for Deflate {
+    fn default() -> Deflate {
+        Deflate {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            threshold: 256,
+        }
+    }
+}
+
+impl Deflate {
+    pub fn new() -> Deflate {
+        Deflate::default()
+    }
+
+    /// The window size the server compresses with, from 9 to 15 bits.
+    /// Smaller windows use less memory per client.
+    pub fn server_max_window_bits(mut self, bits: u8) -> Self {
+        self.server_max_window_bits = bits.max(9).min(15);
+        self
+    }
+
+    /// Ask clients to compress with a window of at most `bits`, from 9 to 15.
+    pub fn client_max_window_bits(mut self, bits: u8) -> Self {
+        self.client_max_window_bits = bits.max(9).min(15);
+        self
+    }
+
+    /// Compress every message on its own, instead of keeping
+    /// the window between messages.
+    pub fn server_no_context_takeover(mut self, enable: bool) -> Self {
+        self.server_no_context_takeover = enable;
+        self
+    }
+
+    /// Ask clients to compress every message on its own.
+    pub fn client_no_context_takeover(mut self, enable: bool) -> Self {
+        self.client_no_context_takeover = enable;
+        self
+    }
+
+    /// Messages smaller than `size` bytes are sent uncompressed.
+    /// Defaults to 256.
+    pub fn threshold(mut self, size: usize) -> Self {
+        self.threshold = size;
+        self
+    }
+
+    /// Picks the first acceptable offer from the values of
+    /// `Sec-WebSocket-Extensions`, returning the response header value.
+    pub(crate) fn negotiate<S: AsRef<str>>(&self, offers: &[S]) -> Option<(Deflate, String)> {
+        offers
+            .iter()
+            .flat_map(|value| value.as_ref().split(','))
+            .filter_map(|offer| self.accept(offer))
+            .next()
+    }
+
+    fn accept(&self, offer: &str) -> Option<(Deflate, String)> {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") {
+            return None;
+        }
+
+        let mut agreed = *self;
+        let mut client_bits = false;
+        for param in params {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            let value = parts.next().map(|v| v.trim().trim_matches('"'));
+            match (name, value) {
+                ("server_no_context_takeover", None) => agreed.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => agreed.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => match bits.parse::<u8>() {
+                    // zlib can not compress with a window of 8 bits
+                    Ok(bits) if bits >= 9 && bits <= 15 => {
+                        agreed.server_max_window_bits = agreed.server_max_window_bits.min(bits)
+                    }
+                    _ => return None,
+                },
+                ("client_max_window_bits", None) => client_bits = true,
+                ("client_max_window_bits", Some(bits)) => match bits.parse::<u8>() {
+                    // The response may not ask for a larger window than offered
+                    Ok(bits) if bits >= 8 && bits <= 15 => {
+                        agreed.client_max_window_bits = agreed.client_max_window_bits.min(bits);
+                        client_bits = true
+                    }
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+
+        let mut response = "permessage-deflate".to_string();
+        if agreed.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if agreed.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if agreed.server_max_window_bits < 15 {
+            response.push_str(&format!(
+                "; server_max_window_bits={}",
+                agreed.server_max_window_bits
+            ));
+        }
+        if client_bits && agreed.client_max_window_bits < 15 {
+            response.push_str(&format!(
+                "; client_max_window_bits={}",
+                agreed.client_max_window_bits
+            ));
+        }
+        Some((agreed, response))
+    }
+}
+
+/// The compression state of a single connection.
+pub(crate) struct DeflateContext {
+    compress: Compress,
+    decompress: Decompress,
+    reset_compress: bool,
+    reset_decompress: bool,
+    threshold: usize,
+}
+
+impl DeflateContext {
+    /// The server side of an agreed `Deflate`.
+    pub(crate) fn server(deflate: &Deflate) -> DeflateContext {
+        DeflateContext {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                deflate.server_max_window_bits,
+            ),
+            // A full window can inflate anything the client sends
+            decompress: Decompress::new(false),
+            reset_compress: deflate.server_no_context_takeover,
+            reset_decompress: deflate.client_no_context_takeover,
+            threshold: deflate.threshold,
+        }
+    }
+
+    pub(crate) fn should_compress(&self, data: &[u8]) -> bool {
+        data.len() >= self.threshold
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, JuntaError> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| JuntaErrorKind::Error(Box::new(e)))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(data.len() / 2 + 64);
+        }
+
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.reset_compress {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// Inflates a message, giving up once it grows past `limit`.
+    pub(crate) fn decompress(
+        &mut self,
+        mut data: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Result<Option<Vec<u8>>, JuntaError> {
+        data.extend_from_slice(&TRAILER);
+        let mut out = Vec::with_capacity(data.len() * 2);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            self.decompress
+                .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| JuntaErrorKind::Error(Box::new(e)))?;
+            if limit.map_or(false, |max| out.len() > max) {
+                return Ok(None);
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(data.len() * 2);
+        }
+
+        if self.reset_decompress {
+            self.decompress.reset(false);
+        }
+        Ok(Some(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deflate() {
+        let deflate = Deflate::new().client_max_window_bits(10).threshold(0);
+
+        let (agreed, response) = deflate
+            .negotiate(&["x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=12; client_max_window_bits"])
+            .unwrap();
+        assert_eq!(
+            response,
+            "permessage-deflate; server_max_window_bits=12; client_max_window_bits=10"
+        );
+        assert!(deflate
+            .negotiate(&["permessage-deflate; unknown"])
+            .is_none());
+
+        // Never more than the client offered
+        let (_, response) = deflate
+            .negotiate(&["permessage-deflate; client_max_window_bits=8"])
+            .unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=8");
+        let (_, response) = deflate
+            .negotiate(&["permessage-deflate; client_max_window_bits=12"])
+            .unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=10");
+        let (_, response) = Deflate::new()
+            .negotiate(&["permessage-deflate; client_max_window_bits=11"])
+            .unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=11");
+
+        let mut server = DeflateContext::server(&agreed);
+        let mut client = DeflateContext::server(&Deflate::new());
+        let text = b"{\"event\":\"message\",\"data\":\"hello hello hello hello\"}";
+        for _ in 0..2 {
+            let compressed = server.compress(text).unwrap();
+            assert!(compressed.len() < text.len());
+            let inflated = client.decompress(compressed, None).unwrap();
+            assert_eq!(inflated.unwrap(), text.to_vec());
+        }
+
+        let compressed = server.compress(&[0; 4096]).unwrap();
+        assert_eq!(client.decompress(compressed, Some(1024)).unwrap(), None);
+    }
+}
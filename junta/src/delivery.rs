@@ -0,0 +1,137 @@
+use super::client::Client;
+use super::error::JuntaError;
+use super::outbox::SendStatus;
+use super::server::MessageContent;
+use future_ext::{OneOfTwo, OneOfTwoFuture};
+use futures::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::prelude::FutureExt;
+use uuid::Uuid;
+
+/// Resolves to a `DeliveryReport`.
+pub type DeliveryFuture = Box<Future<Item = DeliveryReport, Error = JuntaError> + Send>;
+
+/// Why a message did not reach a client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeliveryFailure {
+    /// The connection was closed.
+    Closed,
+    /// The client's queue was full, so the message was dropped
+    /// or the client disconnected, depending on its `SlowConsumer` policy.
+    QueueFull,
+    /// The message was still waiting for room in the queue.
+    TimedOut,
+}
+
+/// The outcome of sending a message to many clients.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct DeliveryReport {
+    attempted: usize,
+    delivered: Vec<Uuid>,
+    failed: Vec<(Uuid, DeliveryFailure)>,
+}
+
+impl DeliveryReport {
+    /// How many clients the message was sent to.
+    pub fn attempted(&self) -> usize {
+        self.attempted
+    }
+
+    /// The clients which have the message queued.
+    pub fn delivered(&self) -> &[Uuid] {
+        &self.delivered
+    }
+
+    pub fn failed(&self) -> &[(Uuid, DeliveryFailure)] {
+        &self.failed
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+fn outcome(status: SendStatus) -> Result<(), DeliveryFailure> {
+    match status {
+        SendStatus::Queued | SendStatus::DroppedOldest => Ok(()),
+        SendStatus::DroppedNewest | SendStatus::Disconnected => Err(DeliveryFailure::QueueFull),
+    }
+}
+
+/// Sends `msg` to every client, waiting at most `timeout` for room in their queues.
+pub(crate) fn deliver(
+    clients: Vec<Arc<Client>>,
+    msg: MessageContent,
+    timeout: Option<Duration>,
+) -> DeliveryFuture {
+    let (binary, data) = msg.to_shared();
+    let sends: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            let id = client.id().clone();
+            let send = client.send_shared(binary, data.clone());
+            let send = match timeout {
+                Some(timeout) => OneOfTwo::First(send.timeout(timeout).then(|ret| {
+                    Ok(match ret {
+                        Ok(status) => outcome(status),
+                        Err(e) if e.is_elapsed() => Err(DeliveryFailure::TimedOut),
+                        Err(_) => Err(DeliveryFailure::Closed),
+                    })
+                })),
+                None => OneOfTwo::Second(send.then(|ret| {
+                    Ok(match ret {
+                        Ok(status) => outcome(status),
+                        Err(_) => Err(DeliveryFailure::Closed),
+                    })
+                })),
+            };
+            OneOfTwoFuture::new(send).map(move |ret| (id, ret))
+        })
+        .collect();
+
+    Box::new(futures::future::join_all(sends).map(|results| {
+        let mut report = DeliveryReport {
+            attempted: results.len(),
+            ..DeliveryReport::default()
+        };
+        for (id, ret) in results {
+            match ret {
+                Ok(()) => report.delivered.push(id),
+                Err(failure) => report.failed.push((id, failure)),
+            }
+        }
+        report
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_delivery_report() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let loopback =
+            Server::loopback().serve_loopback(runtime.executor(), service_fn(|_| Ok(())));
+        let (open, _a) = loopback.connect();
+        let (closed, _b) = loopback.connect();
+        runtime.block_on(closed.close()).unwrap();
+
+        let msg = MessageContent::Text("hello".to_string());
+        let clients = vec![open.clone(), closed.clone()];
+        let report = runtime
+            .block_on(deliver(clients, msg, Some(Duration::from_secs(1))))
+            .unwrap();
+        assert_eq!(report.attempted(), 2);
+        assert_eq!(report.delivered(), &[open.id().clone()]);
+        assert_eq!(
+            report.failed(),
+            &[(closed.id().clone(), DeliveryFailure::Closed)]
+        );
+        assert!(!report.is_complete());
+    }
+}
@@ -1,3 +1,4 @@
+use super::close::CloseCode;
 use junta_service::error::ServiceError;
 use std::error::Error;
 use std::fmt;
@@ -27,6 +28,11 @@ pub enum JuntaErrorKind {
     #[cfg(feature = "encoding")]
     Encoding(EncodingError),
     Transport(WebSocketError),
+    /// The client broke a message limit or the protocol and is closed with this reason.
+    LimitExceeded(websocket::CloseData),
+    /// The code can not be sent in a close frame.
+    InvalidCloseCode(CloseCode),
+    Tls(native_tls::Error),
 }
 
 #[derive(Debug)]
@@ -64,6 +70,12 @@ impl From<WebSocketError> for JuntaError {
     }
 }
 
+impl From<native_tls::Error> for JuntaError {
+    fn from(error: native_tls::Error) -> JuntaError {
+        JuntaError::new(JuntaErrorKind::Tls(error))
+    }
+}
+
 impl From<io::Error> for JuntaError {
     fn from(error: io::Error) -> JuntaError {
         JuntaError::new(JuntaErrorKind::Io(error))
@@ -0,0 +1,296 @@
+use super::client::Client;
+use super::close::CloseCode;
+use super::delivery::DeliveryFuture;
+use super::error::{JuntaError, JuntaErrorKind, JuntaResult};
+use super::metrics::Metrics;
+use super::outbox::SendStatus;
+use super::server::{Broadcast, Broadcaster, MessageContent};
+use atomic_counter::AtomicCounter;
+use future_ext::{OneOfTwo, OneOfTwoFuture};
+use futures::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A cloneable handle to a running server,
+/// for reaching clients from outside of handlers.
+#[derive(Clone)]
+pub struct ServerHandle {
+    server: Arc<Broadcaster>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(server: Arc<Broadcaster>) -> ServerHandle {
+        ServerHandle { server }
+    }
+
+    pub fn clients(&self) -> Vec<Arc<Client>> {
+        self.server
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn client(&self, id: &Uuid) -> Option<Arc<Client>> {
+        self.server.client(id)
+    }
+
+    pub fn send_to(
+        &self,
+        id: &Uuid,
+        msg: MessageContent,
+    ) -> impl Future<Item = SendStatus, Error = JuntaError> {
+        let fut = match self.client(id) {
+            Some(client) => OneOfTwo::First(client.send(msg)),
+            None => OneOfTwo::Second(futures::future::err(JuntaErrorKind::NotFound.into())),
+        };
+        OneOfTwoFuture::new(fut)
+    }
+
+    pub fn send_all(&self, msg: MessageContent) -> impl Future<Item = (), Error = JuntaError> {
+        self.server.send_all(msg)
+    }
+
+    /// Send to every client `predicate` returns true for, eg. based
+    /// on its session, address or protocol.
+    pub fn send_where<F>(
+        &self,
+        predicate: F,
+        msg: MessageContent,
+    ) -> Box<Future<Item = (), Error = JuntaError> + Send>
+    where
+        F: Fn(&Client) -> bool,
+    {
+        Box::new(self.server.send_where(&predicate, msg))
+    }
+
+    /// Send to the clients with the given ids, skipping those no longer connected.
+    /// Named apart from `send_to`, which reaches a single client
+    /// and reports its `SendStatus`.
+    pub fn send_to_many(
+        &self,
+        ids: &[Uuid],
+        msg: MessageContent,
+    ) -> impl Future<Item = (), Error = JuntaError> {
+        self.server.send_to(ids, msg)
+    }
+
+    /// Send to every client, resolving to a report of which clients
+    /// got the message once it is queued everywhere, or `timeout` passes.
+    pub fn send_all_report(
+        &self,
+        msg: MessageContent,
+        timeout: Option<Duration>,
+    ) -> DeliveryFuture {
+        self.server.send_all_report(msg, timeout)
+    }
+
+    /// Close the connection to a client with the given code and reason.
+    pub fn disconnect<S: Into<String>>(
+        &self,
+        id: &Uuid,
+        code: CloseCode,
+        reason: S,
+    ) -> JuntaResult<()> {
+        if !code.is_sendable() {
+            return Err(JuntaErrorKind::InvalidCloseCode(code).into());
+        }
+        match self.client(id) {
+            Some(client) => client.send_close(code.with_reason(reason)),
+            None => Err(JuntaErrorKind::NotFound.into()),
+        }
+    }
+
+    /// The number of currently connected clients.
+    pub fn connections(&self) -> usize {
+        self.server.clients.read().unwrap().len()
+    }
+
+    /// The number of clients connected since the server started.
+    pub fn total_connections(&self) -> usize {
+        self.server.connected.get()
+    }
+
+    /// Connection, message and handler metrics, see `Metrics::render`.
+    pub fn metrics(&self) -> &Metrics {
+        &self.server.metrics
+    }
+
+    /// The number of clients rejected by the connection limits.
+    pub fn rejected_connections(&self) -> usize {
+        self.server.rejected.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use futures::sync::mpsc;
+    use junta_service::prelude::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_server_handle() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (connected, on_connect) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if ctx.message().is_connect() {
+                        connected.unbounded_send(()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let handle = server.handle();
+        let addr = spawn(&mut runtime, server);
+
+        let (sx, rx) = mpsc::unbounded();
+        let _client = runtime
+            .block_on(Client::connect(format!("ws://{}", addr)).unwrap().serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    sx.unbounded_send(ctx.message().clone()).unwrap();
+                    Ok(())
+                }),
+            ))
+            .unwrap();
+        let rx = rx.skip_while(|event| Ok(event.is_connect()));
+        runtime.block_on(on_connect.into_future()).ok().unwrap();
+
+        assert_eq!(handle.connections(), 1);
+        assert_eq!(handle.total_connections(), 1);
+        let id = handle.clients()[0].id().clone();
+
+        let msg = MessageContent::Text("Hello".to_string());
+        let status = runtime.block_on(handle.send_to(&id, msg.clone())).unwrap();
+        assert_eq!(status, SendStatus::Queued);
+        let (event, rx) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(event, Some(ClientEvent::Message(msg)));
+
+        handle
+            .disconnect(&id, CloseCode::Application(4000), "KICKED")
+            .unwrap();
+        let (event, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        match event {
+            Some(ClientEvent::Close(Some(data))) => {
+                assert_eq!((data.status_code, data.reason.as_str()), (4000, "KICKED"))
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    struct Role;
+
+    impl Key for Role {
+        type Value = String;
+    }
+
+    #[test]
+    fn test_send_where() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (joined, on_join) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .protocols(&["v1", "v2"])
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if let ClientEvent::Message(MessageContent::Text(role)) = ctx.message() {
+                        ctx.client().session_mut().insert::<Role>(role.clone());
+                        joined.unbounded_send(()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let handle = server.handle();
+        let addr = spawn(&mut runtime, server);
+
+        let (sx, rx) = mpsc::unbounded();
+        let mut clients = Vec::new();
+        for (name, protocol, role) in &[
+            ("a", "v1", "admin"),
+            ("b", "v2", "admin"),
+            ("c", "v1", "guest"),
+        ] {
+            let sx = sx.clone();
+            let client = runtime
+                .block_on(
+                    Client::connect(format!("ws://{}", addr))
+                        .unwrap()
+                        .protocols(&[protocol])
+                        .serve(
+                            runtime.executor(),
+                            service_fn(move |ctx: Context<ClientEvent>| {
+                                if let ClientEvent::Message(MessageContent::Text(text)) =
+                                    ctx.message()
+                                {
+                                    sx.unbounded_send((*name, text.clone())).unwrap();
+                                }
+                                Ok(())
+                            }),
+                        ),
+                )
+                .unwrap();
+            runtime
+                .block_on(client.send(MessageContent::Text(role.to_string())))
+                .unwrap();
+            clients.push(client);
+        }
+        drop(sx);
+        runtime.block_on(on_join.take(3).collect()).unwrap();
+
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        runtime
+            .block_on(handle.send_where(
+                |c| {
+                    c.session().get::<Role>().map(String::as_str) == Some("admin")
+                        && c.address().ip() == Some(localhost)
+                        && c.protocol() == Some("v1")
+                },
+                MessageContent::Text("targeted".to_string()),
+            ))
+            .unwrap();
+        runtime
+            .block_on(handle.send_where(
+                |c| c.address().ip() != Some(localhost),
+                MessageContent::Text("remote".to_string()),
+            ))
+            .unwrap();
+        runtime
+            .block_on(handle.send_where(|_| true, MessageContent::Text("done".to_string())))
+            .unwrap();
+
+        // Every client sees "done" last, so anything sent to the wrong one shows up before it
+        let (mut received, mut rx, mut done) = (Vec::new(), rx, 0);
+        while done < 3 {
+            let (event, next) = runtime.block_on(rx.into_future()).ok().unwrap();
+            let event = event.unwrap();
+            if event.1 == "done" {
+                done += 1;
+            }
+            received.push(event);
+            rx = next;
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                ("a", "done".to_string()),
+                ("a", "targeted".to_string()),
+                ("b", "done".to_string()),
+                ("c", "done".to_string()),
+            ]
+        );
+    }
+}
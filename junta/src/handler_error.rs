@@ -0,0 +1,63 @@
+use super::client::{Client, ClientEvent};
+use super::context::Context;
+use super::error::JuntaError;
+use future_ext::{OneOfTwo, OneOfTwoFuture};
+use futures::prelude::*;
+use std::sync::Arc;
+
+/// A failed handler call, passed to `ServerBuilder::on_error`.
+pub struct HandlerError {
+    error: JuntaError,
+    event: ClientEvent,
+}
+
+impl HandlerError {
+    pub fn error(&self) -> &JuntaError {
+        &self.error
+    }
+
+    /// The event the handler failed on.
+    pub fn event(&self) -> &ClientEvent {
+        &self.event
+    }
+
+    pub fn into_parts(self) -> (JuntaError, ClientEvent) {
+        (self.error, self.event)
+    }
+}
+
+pub(crate) type ErrorHook = Arc<
+    Fn(Context<HandlerError>) -> Box<Future<Item = (), Error = JuntaError> + Send> + Send + Sync,
+>;
+
+/// A copy of `event` for the error hook, made before the handler runs
+/// as the handler takes the event. Only made when there is a hook,
+/// see `ServerBuilder::on_error` for the cost.
+pub(crate) fn keep(hook: &Option<ErrorHook>, event: &ClientEvent) -> Option<ClientEvent> {
+    hook.as_ref().map(|_| event.clone())
+}
+
+/// Hands a failed handler call to the hook, or logs it if there is none.
+pub(crate) fn report(
+    hook: &Option<ErrorHook>,
+    client: Arc<Client>,
+    event: Option<ClientEvent>,
+    error: JuntaError,
+) -> impl Future<Item = (), Error = ()> {
+    client.metrics.handler_failed();
+    let logger = client.logger().clone();
+    let fut = match (hook, event) {
+        (Some(hook), Some(event)) => {
+            let (ctx, event) = Context::<ClientEvent>::new(client, event).with_message(());
+            let (ctx, _) = ctx.with_message(HandlerError { error, event });
+            OneOfTwo::First(hook(ctx).map_err(move |e| {
+                error!(logger, "error handler failed"; "error" => e.to_string());
+            }))
+        }
+        _ => {
+            error!(logger, "handler failed"; "error" => error.to_string());
+            OneOfTwo::Second(futures::future::ok(()))
+        }
+    };
+    OneOfTwoFuture::new(fut)
+}
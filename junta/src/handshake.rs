@@ -0,0 +1,172 @@
+use super::address::Address;
+use super::http::HttpResponse;
+use super::plugins::{Extensible, Pluggable};
+use typemap::{ShareMap, TypeMap};
+use websocket::server::upgrade::Request;
+
+/// The HTTP request of a websocket upgrade, passed to `ServerBuilder::on_upgrade`.
+pub struct Handshake {
+    method: String,
+    uri: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    address: Address,
+    extensions: ShareMap,
+}
+
+impl Handshake {
+    pub(crate) fn new(request: &Request, address: Address) -> Handshake {
+        Handshake {
+            method: request.subject.0.to_string(),
+            uri: request.subject.1.to_string(),
+            version: request.version.to_string(),
+            headers: request
+                .headers
+                .iter()
+                .map(|h| (h.name().to_string(), h.value_string()))
+                .collect(),
+            address,
+            extensions: TypeMap::custom(),
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The request target, eg. `/chat?token=abc`.
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// eg. `GET /chat?token=abc HTTP/1.1`.
+    pub fn request_line(&self) -> String {
+        format!("{} {} {}", self.method, self.uri, self.version)
+    }
+
+    pub fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or_default()
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.uri.splitn(2, '?').nth(1)
+    }
+
+    /// All request headers as name and value pairs.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The value of a header, the name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.header("Origin")
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|c| {
+                let mut parts = c.splitn(2, '=');
+                Some((parts.next()?.trim(), parts.next()?.trim()))
+            })
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Accept the connection. Anything added to the extensions
+    /// is available from `Client::upgrade_data`.
+    pub fn accept(self) -> Admission {
+        Admission::Accept(self)
+    }
+
+    /// Reject the connection with a HTTP status and body.
+    pub fn reject<S: Into<String>>(self, status: u16, body: S) -> Admission {
+        Admission::Reject(status, body.into())
+    }
+
+    pub(crate) fn into_extensions(self) -> ShareMap {
+        self.extensions
+    }
+}
+
+impl Extensible for Handshake {
+    fn extensions(&self) -> &ShareMap {
+        &self.extensions
+    }
+
+    fn extensions_mut(&mut self) -> &mut ShareMap {
+        &mut self.extensions
+    }
+}
+
+impl Pluggable for Handshake {}
+
+/// The outcome of `ServerBuilder::on_upgrade`.
+pub enum Admission {
+    Accept(Handshake),
+    Reject(u16, String),
+}
+
+/// A minimal HTTP response for rejected upgrades.
+pub(crate) fn rejection(status: u16, body: &str) -> Vec<u8> {
+    HttpResponse::new(status, body).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use typemap::Key;
+
+    struct User;
+
+    impl Key for User {
+        type Value = String;
+    }
+
+    #[test]
+    fn test_handshake() {
+        let headers = vec![
+            ("Cookie".to_string(), "a=1; token=secret".to_string()),
+            ("X-Custom".to_string(), "value".to_string()),
+        ];
+        let mut handshake = Handshake {
+            method: "GET".to_string(),
+            uri: "/chat?room=1".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers,
+            address: Address::Inet("127.0.0.1:1234".parse().unwrap()),
+            extensions: TypeMap::custom(),
+        };
+
+        assert_eq!(handshake.request_line(), "GET /chat?room=1 HTTP/1.1");
+        assert_eq!(handshake.path(), "/chat");
+        assert_eq!(handshake.query(), Some("room=1"));
+        assert_eq!(handshake.cookie("token"), Some("secret"));
+        assert_eq!(handshake.cookie("missing"), None);
+        assert_eq!(handshake.header("x-custom"), Some("value"));
+
+        handshake
+            .extensions_mut()
+            .insert::<User>("rasmus".to_string());
+        match handshake.accept() {
+            Admission::Accept(h) => assert_eq!(
+                h.into_extensions().get::<User>(),
+                Some(&"rasmus".to_string())
+            ),
+            Admission::Reject(..) => panic!("rejected"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use super::address::Address;
+use super::error::JuntaError;
+use super::handle::ServerHandle;
+use super::handshake::Handshake;
+use future_ext::{OneOfTwo, OneOfTwoFuture};
+use futures::prelude::*;
+use slog::Logger;
+use std::sync::Arc;
+use tokio::prelude::{AsyncRead, AsyncWrite};
+
+pub(crate) type HttpHandler = Arc<
+    Fn(HttpRequest) -> Box<Future<Item = HttpResponse, Error = JuntaError> + Send> + Send + Sync,
+>;
+
+/// A plain HTTP request to a path mounted with `ServerBuilder::http`.
+pub struct HttpRequest {
+    request: Handshake,
+    server: ServerHandle,
+}
+
+impl HttpRequest {
+    pub(crate) fn new(request: Handshake, server: ServerHandle) -> HttpRequest {
+        HttpRequest { request, server }
+    }
+
+    pub fn method(&self) -> &str {
+        self.request.method()
+    }
+
+    /// The request target, eg. `/metrics?name=junta`.
+    pub fn uri(&self) -> &str {
+        self.request.uri()
+    }
+
+    pub fn path(&self) -> &str {
+        self.request.path()
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.request.query()
+    }
+
+    /// The value of a header, the name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.request.header(name)
+    }
+
+    pub fn address(&self) -> &Address {
+        self.request.address()
+    }
+
+    /// The server the request was made to, eg. for its metrics.
+    pub fn server(&self) -> &ServerHandle {
+        &self.server
+    }
+}
+
+/// The response to a plain HTTP request.
+pub struct HttpResponse {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// A `text/plain` response.
+    pub fn new<B: Into<Vec<u8>>>(status: u16, body: B) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "text/plain".to_string(),
+            body: body.into(),
+        }
+    }
+
+    pub fn ok<B: Into<Vec<u8>>>(body: B) -> HttpResponse {
+        HttpResponse::new(200, body)
+    }
+
+    pub fn not_found() -> HttpResponse {
+        HttpResponse::new(404, "not found")
+    }
+
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        let mut out = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+        )
+        .into_bytes();
+        out.extend(self.body);
+        out
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Answers a request which is not a websocket upgrade,
+/// with 404 if no handler is mounted on its path.
+pub(crate) fn respond<S>(
+    stream: S,
+    request: HttpRequest,
+    handler: Option<HttpHandler>,
+    logger: Logger,
+) -> impl Future<Item = (), Error = JuntaError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    debug!(logger, "http request"; "method" => request.method(), "path" => request.path());
+    let response = match handler {
+        Some(handler) => OneOfTwo::First(handler(request).or_else(move |e| {
+            warn!(logger, "http handler failed"; "error" => e.to_string());
+            Ok(HttpResponse::new(500, "internal server error"))
+        })),
+        None => OneOfTwo::Second(futures::future::ok(HttpResponse::not_found())),
+    };
+    OneOfTwoFuture::new(response).and_then(move |response| {
+        tokio::io::write_all(stream, response.into_bytes())
+            .map(|_| ())
+            .map_err(JuntaError::from)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use junta_service::prelude::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_http_routes() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .http("/healthz", service_fn(|_| Ok(HttpResponse::ok("ok"))))
+            .http(
+                "/metrics",
+                service_fn(|req: HttpRequest| {
+                    Ok(HttpResponse::ok(req.server().metrics().render())
+                        .content_type("text/plain; version=0.0.4"))
+                }),
+            )
+            .serve(runtime.executor(), service_fn(|_| Ok(())))
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let health = get(addr, "/healthz");
+        assert!(health.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(health.ends_with("\r\n\r\nok"));
+
+        let metrics = get(addr, "/metrics?format=text");
+        assert!(metrics.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(metrics.contains("junta_connections_total 0\n"));
+
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,86 @@
+use super::close::CloseCode;
+use super::error::{JuntaError, JuntaErrorKind};
+use futures::prelude::*;
+use futures::task::AtomicTask;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use websocket::CloseData;
+
+/// The close reason reported when an idle client is closed.
+pub const IDLE_TIMEOUT: &str = "IDLE_TIMEOUT";
+
+/// A client's idle timeout, shared with its connection task
+/// so handlers can change it while the client is connected.
+pub(crate) struct Idle {
+    timeout: Mutex<Option<Duration>>,
+    task: AtomicTask,
+}
+
+impl Idle {
+    pub(crate) fn new(timeout: Option<Duration>) -> Arc<Idle> {
+        Arc::new(Idle {
+            timeout: Mutex::new(timeout),
+            task: AtomicTask::new(),
+        })
+    }
+
+    pub(crate) fn get(&self) -> Option<Duration> {
+        *self.timeout.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+        self.task.notify();
+    }
+}
+
+/// Tracks when a client last sent a message.
+pub(crate) struct IdleTimer {
+    idle: Arc<Idle>,
+    code: CloseCode,
+    last_active: Instant,
+    delay: Option<Delay>,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(idle: Arc<Idle>, code: CloseCode) -> IdleTimer {
+        IdleTimer {
+            idle,
+            code,
+            last_active: Instant::now(),
+            delay: None,
+        }
+    }
+
+    /// Called for every text or binary message from the client.
+    pub(crate) fn active(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// Resolves to the close frame to send once the client has been idle too long.
+    pub(crate) fn poll(&mut self) -> Poll<CloseData, JuntaError> {
+        self.idle.task.register();
+        let deadline = match self.idle.get() {
+            Some(timeout) => self.last_active + timeout,
+            None => {
+                self.delay = None;
+                return Ok(Async::NotReady);
+            }
+        };
+        let delay = match &mut self.delay {
+            Some(delay) => {
+                if delay.deadline() != deadline {
+                    delay.reset(deadline);
+                }
+                delay
+            }
+            None => self.delay.get_or_insert(Delay::new(deadline)),
+        };
+        match delay.poll() {
+            Ok(Async::Ready(_)) => Ok(Async::Ready(self.code.with_reason(IDLE_TIMEOUT))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(JuntaErrorKind::Error(Box::new(e)).into()),
+        }
+    }
+}
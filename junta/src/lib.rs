@@ -1,22 +1,59 @@
 #[macro_use]
 extern crate slog;
 
+mod address;
 mod client;
 #[cfg(feature = "encoding")]
 mod client_ext;
+mod close;
+mod codec;
+mod connector;
 mod context;
+mod deflate;
+mod delivery;
 mod error;
+mod handle;
+mod handler_error;
+mod handshake;
+mod http;
+mod idle;
+mod limits;
+mod loopback;
+mod metrics;
+mod ordering;
+mod outbox;
 pub mod plugins;
 mod server;
+mod session;
+mod stream;
+mod tls;
 //mod utils;
 
 pub mod prelude {
+    pub use super::address::Address;
     pub use super::client::*;
+    pub use super::close::CloseCode;
+    pub use super::codec::{MESSAGE_TOO_BIG, RESERVED_BITS, TOO_MANY_MESSAGES};
     #[cfg(feature = "encoding")]
     pub use super::client_ext::*;
+    pub use super::connector::*;
     pub use super::context::*;
+    pub use super::deflate::Deflate;
+    pub use super::delivery::{DeliveryFailure, DeliveryFuture, DeliveryReport};
     pub use super::error::*;
+    pub use super::handle::*;
+    pub use super::handler_error::HandlerError;
+    pub use super::handshake::{Admission, Handshake};
+    pub use super::http::{HttpRequest, HttpResponse};
+    pub use super::idle::IDLE_TIMEOUT;
+    pub use super::loopback::{Loopback, LoopbackClient};
+    pub use super::metrics::Metrics;
+    pub use super::ordering::Ordering;
+    pub use super::outbox::{SendStatus, SlowConsumer, SLOW_CONSUMER};
     pub use super::plugins;
     pub use super::server::*;
+    pub use super::session::*;
+    pub use super::stream::{BinaryStream, StreamFuture, TakeStream, STREAM_ABORTED};
+    pub use super::tls::*;
     pub use typemap::Key;
 }
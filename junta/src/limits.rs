@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Caps on the connections a server admits.
+#[derive(Clone, Default)]
+pub(crate) struct Limits {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) connection_rate: Option<(usize, Duration)>,
+}
+
+struct State {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Tracks open connections against `Limits`.
+pub(crate) struct Limiter {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: Limits) -> Arc<Limiter> {
+        let tokens = limits.connection_rate.map(|(n, _)| n).unwrap_or(0) as f64;
+        Arc::new(Limiter {
+            limits,
+            state: Mutex::new(State {
+                total: 0,
+                per_ip: HashMap::new(),
+                tokens,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    /// Reserve room for a new connection from `ip`, which is `None`
+    /// for unix socket peers. The permit holds the room until it is
+    /// dropped. Fails with a HTTP status and reason.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+    ) -> Result<Permit, (u16, &'static str)> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(max) = self.limits.max_connections {
+            if state.total >= max {
+                return Err((503, "too many connections"));
+            }
+        }
+
+        if let (Some(max), Some(ip)) = (self.limits.max_connections_per_ip, ip) {
+            if state.per_ip.get(&ip).cloned().unwrap_or(0) >= max {
+                return Err((429, "too many connections from address"));
+            }
+        }
+
+        // Token bucket allowing bursts of up to `count` connections
+        if let Some((count, per)) = self.limits.connection_rate {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled);
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            let per = per.as_secs() as f64 + f64::from(per.subsec_nanos()) * 1e-9;
+            state.tokens = (state.tokens + elapsed * count as f64 / per).min(count as f64);
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                return Err((429, "too many new connections"));
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.total += 1;
+        if let Some(ip) = ip {
+            *state.per_ip.entry(ip).or_insert(0) += 1;
+        }
+
+        Ok(Permit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.total -= 1;
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        let last = match state.per_ip.get_mut(&ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if last {
+            state.per_ip.remove(&ip);
+        }
+    }
+}
+
+/// Room for one connection, released on drop.
+pub(crate) struct Permit {
+    limiter: Arc<Limiter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter() {
+        let limiter = Limiter::new(Limits {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            connection_rate: Some((4, Duration::from_secs(60))),
+        });
+        let (a, b) = (
+            Some("10.0.0.1".parse().unwrap()),
+            Some("10.0.0.2".parse().unwrap()),
+        );
+
+        let first = limiter.admit(a).unwrap();
+        let _second = limiter.admit(a).unwrap();
+        assert_eq!(
+            limiter.admit(a).err(),
+            Some((429, "too many connections from address"))
+        );
+        let _third = limiter.admit(b).unwrap();
+        assert_eq!(limiter.admit(b).err(), Some((503, "too many connections")));
+
+        drop(first);
+        let _fourth = limiter.admit(b).unwrap();
+        drop(_fourth);
+        assert_eq!(
+            limiter.admit(b).err(),
+            Some((429, "too many new connections"))
+        );
+    }
+}
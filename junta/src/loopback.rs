@@ -0,0 +1,256 @@
+use super::address::Address;
+use super::client::{Client, ClientEvent};
+use super::codec::{Codec, MessageLimits};
+use super::connector::attach;
+use super::context::Context;
+use super::error::{JuntaError, JuntaResult};
+use super::handle::ServerHandle;
+use super::outbox::SendStatus;
+use super::server::{Broadcaster, Dispatcher, MessageContent};
+use futures::prelude::*;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+use futures::task::{self, Task};
+use junta_service::error::ServiceError;
+use junta_service::prelude::*;
+use slog::{Discard, Logger};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::codec::Framed;
+use tokio::prelude::{AsyncRead, AsyncWrite};
+use tokio::runtime::TaskExecutor;
+use typemap::TypeMap;
+use websocket::r#async::MsgCodecCtx;
+
+#[derive(Default)]
+struct Buffer {
+    data: Vec<u8>,
+    closed: bool,
+    reader: Option<Task>,
+}
+
+impl Buffer {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+/// One end of an in-memory byte stream.
+pub(crate) struct Pipe {
+    read: Arc<Mutex<Buffer>>,
+    write: Arc<Mutex<Buffer>>,
+}
+
+pub(crate) fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Mutex::new(Buffer::default()));
+    let b = Arc::new(Mutex::new(Buffer::default()));
+    (
+        Pipe {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        Pipe { read: b, write: a },
+    )
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = self.read.lock().unwrap();
+        if read.data.is_empty() {
+            if read.closed {
+                return Ok(0);
+            }
+            read.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(read.data.len());
+        buf[..len].copy_from_slice(&read.data[..len]);
+        read.data.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut write = self.write.lock().unwrap();
+        if write.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        write.data.extend_from_slice(buf);
+        if let Some(task) = write.reader.take() {
+            task.notify();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Pipe {}
+
+impl AsyncWrite for Pipe {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.lock().unwrap().close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close();
+        self.read.lock().unwrap().close();
+    }
+}
+
+/// An in-process server without sockets, for testing handlers.
+/// Created with `ServerBuilder::serve_loopback`.
+pub struct Loopback {
+    server: Arc<Broadcaster>,
+    executor: TaskExecutor,
+    accept: Arc<Fn(Pipe) -> Arc<Client> + Send + Sync>,
+}
+
+impl Loopback {
+    pub(crate) fn new<H>(dispatcher: Dispatcher<H>) -> Loopback
+    where
+        H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
+            + 'static
+            + Send
+            + Sync,
+    {
+        let (server, executor) = (dispatcher.server.clone(), dispatcher.executor.clone());
+        let address = Address::Inet(SocketAddr::from(([127, 0, 0, 1], 0)));
+        Loopback {
+            server,
+            executor,
+            accept: Arc::new(move |io| {
+                let codec = Codec::new(MsgCodecCtx::Server, dispatcher.config.limits)
+                    .stream_binary(dispatcher.config.stream_threshold);
+                let framed = Framed::new(io, codec);
+                dispatcher.connect(framed, address.clone(), None, TypeMap::custom(), None)
+            }),
+        }
+    }
+
+    /// Connects a new client, returning the server side `Client`
+    /// as seen by the handler and the test side of the connection.
+    pub fn connect(&self) -> (Arc<Client>, LoopbackClient) {
+        let (server, client) = pipe();
+        let server = (self.accept)(server);
+
+        let (sx, rx) = unbounded();
+        let handler = service_fn(move |ctx: Context<ClientEvent>| -> JuntaResult<()> {
+            if !ctx.message().is_connect() {
+                sx.unbounded_send(ctx.into_message()).ok();
+            }
+            Ok(())
+        });
+        let codec = Codec::new(MsgCodecCtx::Client, MessageLimits::default());
+        let client = attach(
+            Logger::root(Discard, o! {}),
+            self.executor.clone(),
+            Arc::new(handler),
+            Framed::new(client, codec),
+            server.address().clone(),
+            None,
+        );
+
+        (server, LoopbackClient { client, events: rx })
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.server.clone())
+    }
+}
+
+/// The test side of a loopback connection. A stream of the messages
+/// and close frame sent by the server.
+pub struct LoopbackClient {
+    client: Arc<Client>,
+    events: UnboundedReceiver<ClientEvent>,
+}
+
+impl LoopbackClient {
+    pub fn send(&self, msg: MessageContent) -> impl Future<Item = SendStatus, Error = JuntaError> {
+        self.client.send(msg)
+    }
+
+    pub fn close(&self) -> impl Future<Item = (), Error = JuntaError> {
+        self.client.close()
+    }
+
+    pub fn client(&self) -> &Arc<Client> {
+        &self.client
+    }
+}
+
+impl Stream for LoopbackClient {
+    type Item = ClientEvent;
+    type Error = JuntaError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.events
+            .poll()
+            .map_err(|_| JuntaError::from(ServiceError::ReceiverClosed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use futures::prelude::*;
+    use junta_service::prelude::*;
+    use websocket::CloseData;
+
+    fn text(s: &str) -> MessageContent {
+        MessageContent::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let loopback = Server::loopback()
+            .ordering(Ordering::Sequential)
+            .serve_loopback(
+                runtime.executor(),
+                service_fn(|ctx: Context<ClientEvent>| {
+                    let client = ctx.client().clone();
+                    let fut: Box<Future<Item = (), Error = JuntaError> + Send> = match ctx.message()
+                    {
+                        ClientEvent::Message(MessageContent::Text(t)) if t == "bye" => {
+                            Box::new(client.close())
+                        }
+                        ClientEvent::Message(msg) => Box::new(client.broadcast(msg.clone())),
+                        _ => Box::new(futures::future::ok(())),
+                    };
+                    fut
+                }),
+            );
+
+        let (_, a) = loopback.connect();
+        let (server_b, b) = loopback.connect();
+        assert_eq!(loopback.handle().connections(), 2);
+        assert_eq!(loopback.handle().client(server_b.id()), Some(server_b));
+
+        runtime.block_on(a.send(text("hello"))).unwrap();
+        let (event, b) = runtime.block_on(b.into_future()).ok().unwrap();
+        assert_eq!(event, Some(ClientEvent::Message(text("hello"))));
+
+        runtime.block_on(b.send(text("bye"))).unwrap();
+        // The stream ends once the connection is gone
+        let events: Vec<_> = runtime.block_on(b.collect()).unwrap();
+        assert_eq!(
+            events.first(),
+            Some(&ClientEvent::Close(Some(CloseData::new(
+                1000,
+                "NORMAL".to_string()
+            ))))
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use super::server::MessageContent;
+use atomic_counter::{AtomicCounter, RelaxedCounter};
+use futures::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; 11],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str, value: &str) {
+        let value = escape(value);
+        for (count, bound) in self.counts.iter().zip(BUCKETS.iter()) {
+            writeln!(
+                out,
+                "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}",
+                name, label, value, bound, count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}",
+            name, label, value, self.count
+        )
+        .unwrap();
+        writeln!(out, "{}_sum{{{}=\"{}\"}} {}", name, label, value, self.sum).unwrap();
+        writeln!(
+            out,
+            "{}_count{{{}=\"{}\"}} {}",
+            name, label, value, self.count
+        )
+        .unwrap();
+    }
+}
+
+#[derive(Default)]
+struct Traffic {
+    text: RelaxedCounter,
+    text_bytes: RelaxedCounter,
+    binary: RelaxedCounter,
+    binary_bytes: RelaxedCounter,
+}
+
+impl Traffic {
+    fn count(&self, msg: &MessageContent) {
+        match msg {
+            MessageContent::Text(text) => self.add(false, text.len()),
+            MessageContent::Binary(data) => self.add(true, data.len()),
+        }
+    }
+
+    fn add(&self, binary: bool, bytes: usize) {
+        if binary {
+            self.binary.inc();
+            self.binary_bytes.add(bytes);
+        } else {
+            self.text.inc();
+            self.text_bytes.add(bytes);
+        }
+    }
+
+    fn render(&self, out: &mut String, direction: &str, messages: &str, bytes: &str) {
+        let help = format!("Messages {} by type.", direction);
+        header(out, messages, "counter", &help);
+        writeln!(out, "{}{{type=\"text\"}} {}", messages, self.text.get()).unwrap();
+        writeln!(out, "{}{{type=\"binary\"}} {}", messages, self.binary.get()).unwrap();
+        let help = format!("Payload bytes {} by type.", direction);
+        header(out, bytes, "counter", &help);
+        writeln!(out, "{}{{type=\"text\"}} {}", bytes, self.text_bytes.get()).unwrap();
+        writeln!(
+            out,
+            "{}{{type=\"binary\"}} {}",
+            bytes,
+            self.binary_bytes.get()
+        )
+        .unwrap();
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counters and latency histograms of a server,
+/// rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    connects: RelaxedCounter,
+    disconnects: Mutex<BTreeMap<String, u64>>,
+    received: Traffic,
+    sent: Traffic,
+    handler_errors: RelaxedCounter,
+    handlers: Mutex<BTreeMap<&'static str, Histogram>>,
+    requests: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Arc<Metrics> {
+        Arc::new(Metrics::default())
+    }
+
+    pub(crate) fn connected(&self) {
+        self.connects.inc();
+    }
+
+    /// Counts a closed connection by its close code,
+    /// or as `none` when it ended without a close frame.
+    pub(crate) fn disconnected(&self, code: Option<u16>) {
+        let code = code.map_or_else(|| "none".to_string(), |code| code.to_string());
+        *self.disconnects.lock().unwrap().entry(code).or_insert(0) += 1;
+    }
+
+    pub(crate) fn received(&self, msg: &MessageContent) {
+        self.received.count(msg);
+    }
+
+    /// Counts a streamed binary message once its last chunk arrives.
+    pub(crate) fn received_stream(&self, bytes: usize) {
+        self.received.add(true, bytes);
+    }
+
+    pub(crate) fn sent(&self, binary: bool, bytes: usize) {
+        self.sent.add(binary, bytes);
+    }
+
+    pub(crate) fn handler_failed(&self) {
+        self.handler_errors.inc();
+    }
+
+    /// Times the handler call for an `event`, from the call until its future resolves.
+    pub(crate) fn time<F, T>(self: &Arc<Self>, event: &'static str, call: F) -> Timed<T>
+    where
+        F: FnOnce() -> T,
+        T: Future,
+    {
+        let start = Instant::now();
+        Timed {
+            inner: call(),
+            start,
+            event,
+            metrics: self.clone(),
+        }
+    }
+
+    /// Records the time spent answering a request to `method`.
+    pub fn observe_request(&self, method: &str, elapsed: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "junta_connections_total",
+            "counter",
+            "Accepted connections.",
+        );
+        writeln!(out, "junta_connections_total {}", self.connects.get()).unwrap();
+
+        header(
+            &mut out,
+            "junta_disconnections_total",
+            "counter",
+            "Closed connections by close code.",
+        );
+        for (code, count) in self.disconnects.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "junta_disconnections_total{{code=\"{}\"}} {}",
+                code, count
+            )
+            .unwrap();
+        }
+
+        self.received.render(
+            &mut out,
+            "received",
+            "junta_messages_received_total",
+            "junta_received_bytes_total",
+        );
+        self.sent.render(
+            &mut out,
+            "sent",
+            "junta_messages_sent_total",
+            "junta_sent_bytes_total",
+        );
+
+        header(
+            &mut out,
+            "junta_handler_errors_total",
+            "counter",
+            "Failed handler calls.",
+        );
+        writeln!(
+            out,
+            "junta_handler_errors_total {}",
+            self.handler_errors.get()
+        )
+        .unwrap();
+
+        let name = "junta_handler_duration_seconds";
+        header(&mut out, name, "histogram", "Handler latency by event.");
+        for (event, histogram) in self.handlers.lock().unwrap().iter() {
+            histogram.render(&mut out, name, "event", event);
+        }
+
+        let name = "junta_request_duration_seconds";
+        header(&mut out, name, "histogram", "Request latency by method.");
+        for (method, histogram) in self.requests.lock().unwrap().iter() {
+            histogram.render(&mut out, name, "method", method);
+        }
+
+        out
+    }
+}
+
+/// A handler future which records its latency when it resolves.
+pub(crate) struct Timed<F> {
+    inner: F,
+    start: Instant,
+    event: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let ret = self.inner.poll();
+        match ret {
+            Ok(Async::NotReady) => {}
+            _ => self
+                .metrics
+                .handlers
+                .lock()
+                .unwrap()
+                .entry(self.event)
+                .or_default()
+                .observe(self.start.elapsed()),
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let loopback = Server::loopback()
+            .ordering(Ordering::Sequential)
+            .serve_loopback(
+                runtime.executor(),
+                service_fn(|ctx: Context<ClientEvent>| {
+                    let fut: Box<Future<Item = (), Error = JuntaError> + Send> = match ctx.message()
+                    {
+                        ClientEvent::Message(MessageContent::Text(t)) if t == "fail" => {
+                            Box::new(futures::future::err(
+                                JuntaErrorKind::Unknown("failed".to_string()).into(),
+                            ))
+                        }
+                        ClientEvent::Message(msg) => {
+                            Box::new(ctx.client().send(msg.clone()).map(|_| ()))
+                        }
+                        _ => Box::new(futures::future::ok(())),
+                    };
+                    fut
+                }),
+            );
+        let handle = loopback.handle();
+
+        let (_, client) = loopback.connect();
+        runtime
+            .block_on(client.send(MessageContent::Binary(vec![0; 3])))
+            .unwrap();
+        runtime
+            .block_on(client.send(MessageContent::Text("fail".to_string())))
+            .unwrap();
+        runtime.block_on(client.close()).unwrap();
+        let _: Vec<_> = runtime.block_on(client.collect()).unwrap();
+
+        handle
+            .metrics()
+            .observe_request("user\"s", Duration::from_millis(20));
+
+        let text = handle.metrics().render();
+        for line in &[
+            "junta_connections_total 1",
+            "junta_disconnections_total{code=\"1000\"} 1",
+            "junta_messages_received_total{type=\"text\"} 1",
+            "junta_received_bytes_total{type=\"binary\"} 3",
+            "junta_messages_sent_total{type=\"binary\"} 1",
+            "junta_handler_errors_total 1",
+            "junta_handler_duration_seconds_count{event=\"message\"} 2",
+            "junta_request_duration_seconds_bucket{method=\"user\\\"s\",le=\"0.01\"} 0",
+            "junta_request_duration_seconds_bucket{method=\"user\\\"s\",le=\"0.025\"} 1",
+        ] {
+            assert!(
+                text.contains(&format!("{}\n", line)),
+                "missing {} in {}",
+                line,
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::server::MessageContent;
+use std::sync::Arc;
+
+/// How the messages of a single client are handed to the handler.
+#[derive(Clone)]
+pub enum Ordering {
+    /// Handle the next message only after the previous one is done.
+    Sequential,
+    /// Handle messages as soon as they arrive. This is the default.
+    Concurrent,
+    /// Handle messages with the same key in order,
+    /// and messages with different keys concurrently.
+    Keyed(Arc<Fn(&MessageContent) -> String + Send + Sync>),
+}
+
+impl Ordering {
+    pub fn keyed<F>(key: F) -> Ordering
+    where
+        F: Fn(&MessageContent) -> String + Send + Sync + 'static,
+    {
+        Ordering::Keyed(Arc::new(key))
+    }
+}
+
+impl Default for Ordering {
+    fn default() -> Ordering {
+        Ordering::Concurrent
+    }
+}
+
+/// Where a single inbound frame is run.
+pub(crate) enum Lane {
+    Spawn,
+    Inline,
+    Keyed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use futures::future;
+    use futures::prelude::*;
+    use futures::sync::{mpsc, oneshot};
+    use junta_service::prelude::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::timer::Delay;
+
+    #[test]
+    fn test_sequential() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .ordering(Ordering::Sequential)
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    let sx = sx.clone();
+                    let msg = match ctx.message() {
+                        ClientEvent::Message(msg) => msg.clone(),
+                        _ => return future::Either::A(future::ok(())),
+                    };
+                    // The first message takes the longest
+                    let wait = match &msg {
+                        MessageContent::Text(text) if text == "first" => 50,
+                        _ => 0,
+                    };
+                    future::Either::B(
+                        Delay::new(Instant::now() + Duration::from_millis(wait))
+                            .map_err(|e| JuntaErrorKind::Error(Box::new(e)).into())
+                            .map(move |_| sx.unbounded_send(msg).unwrap()),
+                    )
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let client = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addr))
+                    .unwrap()
+                    .serve(runtime.executor(), service_fn(|_| Ok(()))),
+            )
+            .unwrap();
+        for text in &["first", "second"] {
+            runtime
+                .block_on(client.send(MessageContent::Text(text.to_string())))
+                .unwrap();
+        }
+
+        let handled = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            handled,
+            vec![
+                MessageContent::Text("first".to_string()),
+                MessageContent::Text("second".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keyed() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // "a:1" is only done once "b:1", sent after "a:2", has been handled
+        let (open, gate) = oneshot::channel::<()>();
+        let open = Arc::new(Mutex::new(Some(open)));
+        let gate = Arc::new(Mutex::new(Some(gate)));
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .ordering(Ordering::keyed(|msg| match msg {
+                MessageContent::Text(text) => text.split(':').next().unwrap().to_string(),
+                _ => String::new(),
+            }))
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    let sx = sx.clone();
+                    let text = match ctx.message() {
+                        ClientEvent::Message(MessageContent::Text(text)) => text.clone(),
+                        _ => return future::Either::A(future::ok(())),
+                    };
+                    // Runs as soon as the handler is called
+                    sx.unbounded_send(format!("start {}", text)).unwrap();
+                    let open = open.clone();
+                    let wait = match text.as_str() {
+                        "a:1" => future::Either::A(gate.lock().unwrap().take().unwrap()),
+                        _ => future::Either::B(future::ok(())),
+                    };
+                    future::Either::B(
+                        wait.map_err(|e| JuntaErrorKind::Error(Box::new(e)).into())
+                            .map(move |_| {
+                                sx.unbounded_send(text.clone()).unwrap();
+                                if text == "b:1" {
+                                    open.lock().unwrap().take().unwrap().send(()).unwrap();
+                                }
+                            }),
+                    )
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let client = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addr))
+                    .unwrap()
+                    .serve(runtime.executor(), service_fn(|_| Ok(()))),
+            )
+            .unwrap();
+        for text in &["a:1", "a:2", "b:1"] {
+            runtime
+                .block_on(client.send(MessageContent::Text(text.to_string())))
+                .unwrap();
+        }
+
+        let log: Vec<_> = runtime.block_on(rx.take(6).collect()).unwrap();
+        let handled: Vec<_> = log.iter().filter(|s| !s.starts_with("start")).collect();
+        assert_eq!(handled, vec!["b:1", "a:1", "a:2"]);
+        // The handler is not even called for "a:2" before "a:1" is done
+        let at = |entry: &str| log.iter().position(|s| s == entry).unwrap();
+        assert!(at("start a:2") > at("a:1"));
+    }
+}
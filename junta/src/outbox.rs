@@ -0,0 +1,435 @@
+use super::close::CloseCode;
+use super::codec::Frame;
+use super::error::{JuntaError, JuntaErrorKind, JuntaResult};
+use futures::prelude::*;
+use futures::task::{self, AtomicTask, Task};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use websocket::{CloseData, OwnedMessage};
+
+/// What to do when a client's outbound queue is full.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SlowConsumer {
+    /// Wait until there is room in the queue.
+    Wait,
+    /// Drop the message being sent.
+    DropNewest,
+    /// Drop the oldest queued message to make room. Drops the message
+    /// being sent instead while a message is streamed, or if only its chunks are queued.
+    DropOldest,
+    /// Close the connection with the given code,
+    /// eg. `PolicyViolation` or `TryAgainLater`.
+    Disconnect(CloseCode),
+}
+
+/// The outcome of sending a message to a client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SendStatus {
+    Queued,
+    DroppedNewest,
+    DroppedOldest,
+    Disconnected,
+}
+
+/// The close reason reported when a slow client is disconnected.
+pub const SLOW_CONSUMER: &str = "SLOW_CONSUMER";
+
+struct State {
+    queue: VecDeque<Frame>,
+    waiting: Vec<Task>,
+    closed: bool,
+    /// A fragmented message is being queued, other messages wait for it
+    streaming: bool,
+    reason: Option<CloseData>,
+}
+
+/// Bounded queue of messages waiting to be written to a client.
+pub(crate) struct Outbox {
+    state: Mutex<State>,
+    capacity: usize,
+    reader: AtomicTask,
+}
+
+impl Outbox {
+    pub(crate) fn new(capacity: usize) -> Arc<Outbox> {
+        Arc::new(Outbox {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                waiting: Vec::new(),
+                closed: false,
+                streaming: false,
+                reason: None,
+            }),
+            capacity,
+            reader: AtomicTask::new(),
+        })
+    }
+
+    /// Queue a message, applying `policy` if the queue is full.
+    /// `msg` is left in place when the message has to wait.
+    pub(crate) fn poll_push(
+        &self,
+        msg: &mut Option<Frame>,
+        policy: SlowConsumer,
+    ) -> Poll<SendStatus, JuntaError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(JuntaErrorKind::Send.into());
+        }
+
+        // No message may go out between the chunks of a streamed one,
+        // so the queue is full to everything else until it ends
+        let status = if !state.streaming && state.queue.len() < self.capacity {
+            SendStatus::Queued
+        } else {
+            match policy {
+                SlowConsumer::Wait => {
+                    state.waiting.push(task::current());
+                    return Ok(Async::NotReady);
+                }
+                SlowConsumer::DropNewest => {
+                    msg.take();
+                    return Ok(Async::Ready(SendStatus::DroppedNewest));
+                }
+                SlowConsumer::DropOldest => {
+                    // Chunks of a streamed message can not be dropped
+                    let oldest = if state.streaming {
+                        None
+                    } else {
+                        state.queue.iter().position(|frame| match frame {
+                            Frame::Chunk { .. } => false,
+                            _ => true,
+                        })
+                    };
+                    match oldest {
+                        Some(oldest) => {
+                            state.queue.remove(oldest);
+                            SendStatus::DroppedOldest
+                        }
+                        None => {
+                            msg.take();
+                            return Ok(Async::Ready(SendStatus::DroppedNewest));
+                        }
+                    }
+                }
+                SlowConsumer::Disconnect(code) => {
+                    msg.take();
+                    let reason = code.with_reason(SLOW_CONSUMER);
+                    self.disconnect_locked(&mut state, reason);
+                    return Ok(Async::Ready(SendStatus::Disconnected));
+                }
+            }
+        };
+
+        state.queue.extend(msg.take());
+        self.reader.notify();
+        Ok(Async::Ready(status))
+    }
+
+    /// Start queueing a fragmented message, once no other is being queued.
+    pub(crate) fn poll_begin_stream(&self) -> Poll<(), JuntaError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(JuntaErrorKind::Send.into());
+        }
+        if state.streaming {
+            state.waiting.push(task::current());
+            return Ok(Async::NotReady);
+        }
+        state.streaming = true;
+        Ok(Async::Ready(()))
+    }
+
+    /// Queue a chunk of the message started with `poll_begin_stream`,
+    /// waiting for room regardless of the slow consumer policy.
+    pub(crate) fn poll_push_chunk(&self, chunk: &mut Option<Frame>) -> Poll<(), JuntaError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(JuntaErrorKind::Send.into());
+        }
+        if state.queue.len() >= self.capacity {
+            state.waiting.push(task::current());
+            return Ok(Async::NotReady);
+        }
+        state.queue.extend(chunk.take());
+        self.reader.notify();
+        Ok(Async::Ready(()))
+    }
+
+    /// Let other messages through again.
+    pub(crate) fn end_stream(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.streaming = false;
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+    }
+
+    /// Queue a control frame, ignoring the capacity.
+    pub(crate) fn push_control(&self, msg: OwnedMessage) -> JuntaResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(JuntaErrorKind::Send.into());
+        }
+        state.queue.push_back(Frame::Message(msg));
+        self.reader.notify();
+        Ok(())
+    }
+
+    /// Queue a final frame and stop accepting messages.
+    /// Returns false if the outbox was already closed.
+    pub(crate) fn close(&self, msg: OwnedMessage) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        state.queue.push_back(Frame::Message(msg));
+        self.close_locked(&mut state);
+        true
+    }
+
+    /// Drop queued messages and close with `reason`,
+    /// which is reported as the reason the connection ended.
+    pub(crate) fn disconnect(&self, reason: CloseData) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        self.disconnect_locked(&mut state, reason);
+        true
+    }
+
+    fn disconnect_locked(&self, state: &mut State, reason: CloseData) {
+        state.queue.clear();
+        state
+            .queue
+            .push_back(Frame::Message(OwnedMessage::Close(Some(reason.clone()))));
+        state.reason = Some(reason);
+        self.close_locked(state);
+    }
+
+    fn close_locked(&self, state: &mut State) {
+        state.closed = true;
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+        self.reader.notify();
+    }
+}
+
+/// Stream of queued messages, ending once the outbox is closed and empty.
+pub(crate) struct OutboxReader {
+    outbox: Arc<Outbox>,
+}
+
+impl OutboxReader {
+    pub(crate) fn new(outbox: Arc<Outbox>) -> OutboxReader {
+        OutboxReader { outbox }
+    }
+
+    /// Why the outbox was closed, if it was closed by a policy.
+    pub(crate) fn reason(&self) -> Option<CloseData> {
+        self.outbox.state.lock().unwrap().reason.clone()
+    }
+
+    pub(crate) fn disconnect(&self, reason: CloseData) -> bool {
+        self.outbox.disconnect(reason)
+    }
+
+    pub(crate) fn push_control(&self, msg: OwnedMessage) -> JuntaResult<()> {
+        self.outbox.push_control(msg)
+    }
+}
+
+impl Stream for OutboxReader {
+    type Item = Frame;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.outbox.reader.register();
+        let mut state = self.outbox.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(msg) => {
+                for task in state.waiting.drain(..) {
+                    task.notify();
+                }
+                Ok(Async::Ready(Some(msg)))
+            }
+            None if state.closed => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for OutboxReader {
+    fn drop(&mut self) {
+        let outbox = self.outbox.clone();
+        let mut state = outbox.state.lock().unwrap();
+        outbox.close_locked(&mut state);
+    }
+}
+
+/// Future returned by `Client::send`.
+pub struct SendFuture {
+    outbox: Arc<Outbox>,
+    msg: Option<Frame>,
+    policy: SlowConsumer,
+}
+
+impl SendFuture {
+    pub(crate) fn new(outbox: Arc<Outbox>, msg: Frame, policy: SlowConsumer) -> SendFuture {
+        SendFuture {
+            outbox,
+            msg: Some(msg),
+            policy,
+        }
+    }
+}
+
+impl Future for SendFuture {
+    type Item = SendStatus;
+    type Error = JuntaError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.outbox.poll_push(&mut self.msg, self.policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Frame {
+        OwnedMessage::Text(s.to_string()).into()
+    }
+
+    fn drain(outbox: &Arc<Outbox>) -> Vec<Frame> {
+        let mut reader = OutboxReader::new(outbox.clone());
+        futures::future::lazy(move || {
+            let mut out = Vec::new();
+            while let Ok(Async::Ready(Some(msg))) = reader.poll() {
+                out.push(msg);
+            }
+            Ok::<_, ()>(out)
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_slow_consumer_policies() {
+        let outbox = Outbox::new(1);
+        let send = |msg, policy| SendFuture::new(outbox.clone(), msg, policy).wait().unwrap();
+
+        assert_eq!(send(text("1"), SlowConsumer::Wait), SendStatus::Queued);
+        assert_eq!(
+            send(text("2"), SlowConsumer::DropNewest),
+            SendStatus::DroppedNewest
+        );
+        assert_eq!(
+            send(text("3"), SlowConsumer::DropOldest),
+            SendStatus::DroppedOldest
+        );
+        assert_eq!(
+            send(
+                text("4"),
+                SlowConsumer::Disconnect(CloseCode::TryAgainLater)
+            ),
+            SendStatus::Disconnected
+        );
+        assert!(
+            SendFuture::new(outbox.clone(), text("5"), SlowConsumer::Wait)
+                .wait()
+                .is_err()
+        );
+
+        let close = CloseData::new(1013, SLOW_CONSUMER.to_string());
+        assert_eq!(
+            drain(&outbox),
+            vec![OwnedMessage::Close(Some(close)).into()]
+        );
+    }
+
+    #[test]
+    fn test_drop_oldest_while_streaming() {
+        let outbox = Outbox::new(1);
+        futures::future::lazy(|| {
+            assert_eq!(outbox.poll_begin_stream().unwrap(), Async::Ready(()));
+            let mut chunk = Some(Frame::Chunk {
+                data: vec![1],
+                first: true,
+                finished: true,
+            });
+            assert_eq!(
+                outbox.poll_push_chunk(&mut chunk).unwrap(),
+                Async::Ready(())
+            );
+
+            // Nothing can be dropped for it while streaming
+            let mut msg = Some(text("1"));
+            assert_eq!(
+                outbox
+                    .poll_push(&mut msg, SlowConsumer::DropOldest)
+                    .unwrap(),
+                Async::Ready(SendStatus::DroppedNewest)
+            );
+            outbox.end_stream();
+
+            // Only the chunk is queued, so the new message is dropped
+            let mut msg = Some(text("2"));
+            assert_eq!(
+                outbox
+                    .poll_push(&mut msg, SlowConsumer::DropOldest)
+                    .unwrap(),
+                Async::Ready(SendStatus::DroppedNewest)
+            );
+            assert_eq!(msg, None);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(
+            drain(&outbox),
+            vec![Frame::Chunk {
+                data: vec![1],
+                first: true,
+                finished: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_drop_newest_while_streaming() {
+        let outbox = Outbox::new(4);
+        futures::future::lazy(|| {
+            assert_eq!(outbox.poll_begin_stream().unwrap(), Async::Ready(()));
+
+            // There is room, but not between the chunks
+            let mut msg = Some(text("1"));
+            assert_eq!(
+                outbox
+                    .poll_push(&mut msg, SlowConsumer::DropNewest)
+                    .unwrap(),
+                Async::Ready(SendStatus::DroppedNewest)
+            );
+            assert_eq!(msg, None);
+
+            let mut msg = Some(text("2"));
+            assert_eq!(
+                outbox.poll_push(&mut msg, SlowConsumer::Wait).unwrap(),
+                Async::NotReady
+            );
+            outbox.end_stream();
+            assert_eq!(
+                outbox.poll_push(&mut msg, SlowConsumer::Wait).unwrap(),
+                Async::Ready(SendStatus::Queued)
+            );
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(drain(&outbox), vec![text("2")]);
+    }
+}
@@ -1,20 +1,54 @@
-use super::client::{Client, ClientEvent, ClientFuture};
+use super::address::{Address, Peer};
+use super::client::{Client, ClientEvent, ClientFuture, Incoming};
+use super::close::CloseCode;
+use super::codec::{Codec, MessageLimits};
 use super::context::Context;
+use super::deflate::{Deflate, DeflateContext, EXTENSIONS};
+use super::delivery::{deliver, DeliveryFuture};
 use super::error::{JuntaError, JuntaErrorKind, JuntaResult};
+use super::handle::ServerHandle;
+use super::handler_error::{keep, report, ErrorHook, HandlerError};
+use super::handshake::{rejection, Admission, Handshake};
+use super::http::{respond, HttpHandler, HttpRequest, HttpResponse};
+use super::idle::Idle;
+use super::limits::{Limiter, Limits, Permit};
+use super::loopback::Loopback;
+use super::metrics::Metrics;
+use super::ordering::{Lane, Ordering};
+use super::outbox::{Outbox, OutboxReader, SlowConsumer};
+use super::stream::TakeStream;
+use super::tls::TlsIdentity;
+use atomic_counter::AtomicCounter;
+use bytes::Bytes;
 use future_ext::{OneOfFour, OneOfFourFuture, OneOfTwo, OneOfTwoFuture};
+use futures::future::Either;
 use futures::prelude::*;
+use futures::sync::mpsc::{channel, Sender};
+use futures::sync::oneshot::Receiver as OneReceiver;
 use junta_service::prelude::*;
 use slog::{Discard, Logger};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
-use tokio::reactor::Handle;
+use std::time::Duration;
+use tokio::codec::Framed;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::prelude::{AsyncRead, AsyncWrite, FutureExt};
 use tokio::runtime::TaskExecutor;
+use tokio_tls::TlsAcceptor;
+use typemap::{ShareMap, TypeMap};
 use uuid::Uuid;
-use websocket::message::OwnedMessage;
-use websocket::r#async::Server as WSServer;
-use websocket::server::InvalidConnection;
+use websocket::header::Headers;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::r#async::server::upgrade::{IntoWs, Upgrade};
+use websocket::r#async::MsgCodecCtx;
 use websocket::WebSocketError;
 
 pub type ClientList = Arc<RwLock<HashMap<Uuid, Arc<Client>>>>;
@@ -23,53 +57,209 @@ pub trait Broadcast {
     type Future: Future<Item = (), Error = JuntaError> + Send + 'static;
     fn send_all(&self, msg: MessageContent) -> Self::Future;
     fn broadcast(&self, client: &Client, msg: MessageContent) -> Self::Future;
+    /// Like `send_all`, but resolves once every client has the message
+    /// queued, or `timeout` has passed, reporting who it reached.
+    fn send_all_report(&self, msg: MessageContent, timeout: Option<Duration>) -> DeliveryFuture;
+    /// Like `broadcast`, reporting who the message reached.
+    fn broadcast_report(
+        &self,
+        client: &Client,
+        msg: MessageContent,
+        timeout: Option<Duration>,
+    ) -> DeliveryFuture;
+    /// Send to every client `predicate` returns true for.
+    fn send_where(&self, predicate: &Fn(&Client) -> bool, msg: MessageContent) -> Self::Future;
+    /// Send to the given clients, skipping those no longer connected.
+    fn send_to(&self, ids: &[Uuid], msg: MessageContent) -> Self::Future;
     fn client(&self, id: &Uuid) -> Option<Arc<Client>>;
+    /// Add the client to a room, creating it if needed.
+    fn join(&self, room: &str, client: &Client);
+    /// Remove the client from a room, dropping the room when it is empty.
+    fn leave(&self, room: &str, client: &Client);
+    /// Send to every member of a room, optionally except one client.
+    fn send_to_room(
+        &self,
+        room: &str,
+        msg: MessageContent,
+        except: Option<&Client>,
+    ) -> Self::Future;
+    fn members(&self, room: &str) -> Vec<Arc<Client>>;
+    /// The rooms the client is a member of.
+    fn rooms(&self, client: &Client) -> Vec<String>;
 }
 
-struct Broadcaster {
-    clients: ClientList,
-    executor: TaskExecutor,
+pub(crate) struct Broadcaster {
+    pub(crate) clients: ClientList,
+    pub(crate) executor: TaskExecutor,
+    pub(crate) shutdown: RwLock<Option<CloseData>>,
+    rooms: RwLock<HashMap<String, HashSet<Uuid>>>,
+    pub(crate) connected: atomic_counter::RelaxedCounter,
+    pub(crate) rejected: atomic_counter::RelaxedCounter,
+    pub(crate) metrics: Arc<Metrics>,
 }
 
-impl Broadcast for Broadcaster {
-    type Future = futures::future::FutureResult<(), JuntaError>; // Box<Future<Item = (), Error = JuntaError> + Send + 'static>;
-    fn send_all(&self, msg: MessageContent) -> Self::Future {
-        let clients = self.clients.read().unwrap();
-        let mut promises = Vec::new();
-        for (_, v) in clients.iter() {
-            promises.push(v.send(msg.clone()))
+impl Broadcaster {
+    pub(crate) fn new(executor: TaskExecutor) -> Broadcaster {
+        Broadcaster {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            executor,
+            shutdown: RwLock::new(None),
+            rooms: RwLock::new(HashMap::new()),
+            connected: atomic_counter::RelaxedCounter::new(0),
+            rejected: atomic_counter::RelaxedCounter::new(0),
+            metrics: Metrics::new(),
         }
+    }
+
+    /// Queues `msg` for each client in the background,
+    /// all of them sharing the one payload.
+    fn spawn_sends(&self, clients: Vec<Arc<Client>>, msg: MessageContent) {
+        let (binary, data) = msg.to_shared();
+        let promises: Vec<_> = clients
+            .iter()
+            .map(|v| v.send_shared(binary, data.clone()))
+            .collect();
         self.executor.spawn(
             futures::future::join_all(promises)
                 .map(|_| ())
                 .map_err(|_| ()),
         );
+    }
+
+    /// Removes a closed client from all rooms.
+    pub(crate) fn leave_all(&self, id: &Uuid) {
+        self.rooms.write().unwrap().retain(|_, members| {
+            members.remove(id);
+            !members.is_empty()
+        });
+    }
+}
 
+impl Broadcast for Broadcaster {
+    type Future = futures::future::FutureResult<(), JuntaError>; // Box<Future<Item = (), Error = JuntaError> + Send + 'static>;
+    fn send_all(&self, msg: MessageContent) -> Self::Future {
+        let clients = self.clients.read().unwrap().values().cloned().collect();
+        self.spawn_sends(clients, msg);
         futures::future::ok(())
-        // Box::new(futures::future::join_all(promises).map(|_| ()))
     }
 
     fn broadcast(&self, client: &Client, msg: MessageContent) -> Self::Future {
-        let clients = self.clients.read().unwrap();
-        let mut promises = Vec::new();
-        for (_, v) in clients.iter() {
-            if v.as_ref() == client {
-                continue;
-            }
-            promises.push(v.send(msg.clone()))
-        }
-        self.executor.spawn(
-            futures::future::join_all(promises)
-                .map(|_| ())
-                .map_err(|_| ()),
-        );
+        let clients = self
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .filter(|v| v.as_ref() != client)
+            .cloned()
+            .collect();
+        self.spawn_sends(clients, msg);
+        futures::future::ok(())
+    }
+
+    fn send_all_report(&self, msg: MessageContent, timeout: Option<Duration>) -> DeliveryFuture {
+        let clients = self.clients.read().unwrap().values().cloned().collect();
+        deliver(clients, msg, timeout)
+    }
+
+    fn broadcast_report(
+        &self,
+        client: &Client,
+        msg: MessageContent,
+        timeout: Option<Duration>,
+    ) -> DeliveryFuture {
+        let clients = self
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .filter(|v| v.as_ref() != client)
+            .cloned()
+            .collect();
+        deliver(clients, msg, timeout)
+    }
+
+    fn send_where(&self, predicate: &Fn(&Client) -> bool, msg: MessageContent) -> Self::Future {
+        // Not holding the lock while the predicate runs
+        let clients: Vec<_> = self.clients.read().unwrap().values().cloned().collect();
+        let clients = clients.into_iter().filter(|v| predicate(v)).collect();
+        self.spawn_sends(clients, msg);
+        futures::future::ok(())
+    }
 
+    fn send_to(&self, ids: &[Uuid], msg: MessageContent) -> Self::Future {
+        let clients = {
+            let clients = self.clients.read().unwrap();
+            ids.iter()
+                .filter_map(|id| clients.get(id).cloned())
+                .collect()
+        };
+        self.spawn_sends(clients, msg);
         futures::future::ok(())
     }
 
     fn client(&self, id: &Uuid) -> Option<Arc<Client>> {
         self.clients.read().unwrap().get(id).map(|c| c.clone())
     }
+
+    fn join(&self, room: &str, client: &Client) {
+        self.rooms
+            .write()
+            .unwrap()
+            .entry(room.to_string())
+            .or_insert_with(HashSet::new)
+            .insert(client.id.clone());
+    }
+
+    fn leave(&self, room: &str, client: &Client) {
+        let mut rooms = self.rooms.write().unwrap();
+        let empty = match rooms.get_mut(room) {
+            Some(members) => {
+                members.remove(&client.id);
+                members.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            rooms.remove(room);
+        }
+    }
+
+    fn send_to_room(
+        &self,
+        room: &str,
+        msg: MessageContent,
+        except: Option<&Client>,
+    ) -> Self::Future {
+        let clients = self
+            .members(room)
+            .into_iter()
+            .filter(|v| Some(v.as_ref()) != except)
+            .collect();
+        self.spawn_sends(clients, msg);
+        futures::future::ok(())
+    }
+
+    fn members(&self, room: &str) -> Vec<Arc<Client>> {
+        let rooms = self.rooms.read().unwrap();
+        let clients = self.clients.read().unwrap();
+        match rooms.get(room) {
+            Some(members) => members
+                .iter()
+                .filter_map(|id| clients.get(id).cloned())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn rooms(&self, client: &Client) -> Vec<String> {
+        self.rooms
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, members)| members.contains(&client.id))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -85,11 +275,48 @@ impl MessageContent {
             MessageContent::Binary(bs) => OwnedMessage::Binary(bs),
         }
     }
+
+    /// Whether the message is binary, and its payload in a buffer
+    /// which clients can share, see `Client::send_shared`.
+    pub(crate) fn to_shared(self) -> (bool, Bytes) {
+        match self {
+            MessageContent::Text(text) => (false, Bytes::from(text)),
+            MessageContent::Binary(bs) => (true, Bytes::from(bs)),
+        }
+    }
+}
+
+/// Where a server accepts connections.
+enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+struct Listener {
+    listen: Listen,
+    tls: Option<TlsIdentity>,
+}
+
+impl Listener {
+    fn new(listen: Listen, tls: Option<TlsIdentity>) -> Listener {
+        Listener { listen, tls }
+    }
 }
 
 pub struct ServerBuilder {
-    addr: SocketAddr,
+    listeners: Vec<Listener>,
+    tls: Option<TlsIdentity>,
     logger: Logger,
+    protocols: Vec<String>,
+    allow_no_protocol: bool,
+    deflate: Option<Deflate>,
+    shutdown_close: CloseData,
+    shutdown_timeout: Duration,
+    config: ClientConfig,
+    on_upgrade: Option<UpgradeHook>,
+    limits: Limits,
+    routes: HashMap<String, HttpHandler>,
     // executor: TaskExecutor,
 }
 
@@ -99,19 +326,272 @@ impl ServerBuilder {
         self
     }
 
+    /// Accept `wss://` connections on every TCP listener, including ones
+    /// added later, using the given certificate and key.
+    /// Use `listen_tls` to secure a single listener instead.
+    pub fn tls(mut self, identity: TlsIdentity) -> Self {
+        self.tls = Some(identity);
+        self
+    }
+
+    /// Also accept connections on `addr`. Every listener shares
+    /// the same clients, broadcasts and handler.
+    pub fn listen<S: ToSocketAddrs>(mut self, addr: S) -> JuntaResult<Self> {
+        let addr = resolve(addr)?;
+        self.listeners.push(Listener::new(Listen::Tcp(addr), None));
+        Ok(self)
+    }
+
+    /// Also accept `wss://` connections on `addr`.
+    pub fn listen_tls<S: ToSocketAddrs>(
+        mut self,
+        addr: S,
+        identity: TlsIdentity,
+    ) -> JuntaResult<Self> {
+        let addr = resolve(addr)?;
+        self.listeners
+            .push(Listener::new(Listen::Tcp(addr), Some(identity)));
+        Ok(self)
+    }
+
+    /// Also accept connections on a unix domain socket, see `Server::bind_unix`.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        self.listeners.push(Listener::new(Listen::Unix(path), None));
+        self
+    }
+
+    /// Subprotocols to accept, in order of preference.
+    /// Defaults to `rust-websocket`.
+    pub fn protocols<S: AsRef<str>>(mut self, protocols: &[S]) -> Self {
+        self.protocols = protocols.iter().map(|p| p.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Accept clients which do not request any subprotocol.
+    pub fn allow_no_protocol(mut self, allow: bool) -> Self {
+        self.allow_no_protocol = allow;
+        self
+    }
+
+    /// Compress messages with permessage-deflate,
+    /// for clients which offer it during the upgrade.
+    pub fn deflate(mut self, deflate: Deflate) -> Self {
+        self.deflate = Some(deflate);
+        self
+    }
+
+    /// The close frame sent to every client on shutdown.
+    /// Defaults to `1001 GOING_AWAY`.
+    pub fn shutdown_close<S: Into<String>>(mut self, code: CloseCode, reason: S) -> Self {
+        self.shutdown_close = code.with_reason(reason);
+        self
+    }
+
+    /// The most clients connected at once. Clients over the limit
+    /// are rejected with `503 Service Unavailable`.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// The most clients connected at once from a single IP address.
+    /// Clients over the limit are rejected with `429 Too Many Requests`.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.limits.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Accept at most `count` new clients every `per`, allowing short bursts.
+    /// Clients over the limit are rejected with `429 Too Many Requests`.
+    pub fn connection_rate(mut self, count: usize, per: Duration) -> Self {
+        self.limits.connection_rate = Some((count, per));
+        self
+    }
+
+    /// How long to wait for clients and handlers to finish on shutdown.
+    /// Defaults to 5 seconds.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Ping every client on `interval`, closing those which have not
+    /// sent anything for `timeout`. Their `ClientEvent::Close` carries
+    /// the reason `HEARTBEAT_TIMEOUT`.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// Close clients which have sent no text or binary message for `timeout`,
+    /// regardless of pings. Their `ClientEvent::Close` carries the reason
+    /// `IDLE_TIMEOUT`. Handlers can change it per client with `Client::set_idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// The close code sent to idle clients. Defaults to `CloseCode::GoingAway`.
+    pub fn idle_close_code(mut self, code: CloseCode) -> Self {
+        self.config.idle_code = code;
+        self
+    }
+
+    /// Hand binary messages larger than `threshold` bytes to the handler
+    /// as a `ClientEvent::Stream` of their fragments, instead of buffering
+    /// them whole. Compressed messages are always buffered. Streams are
+    /// handled concurrently with `Ordering::Keyed`, as they have no key.
+    pub fn stream_binary(mut self, threshold: usize) -> Self {
+        self.config.stream_threshold = Some(threshold);
+        self
+    }
+
+    /// How long a closing connection waits for the client to answer
+    /// its close frame before it is dropped. Defaults to 1 second.
+    pub fn close_grace_period(mut self, grace: Duration) -> Self {
+        self.config.close_grace = grace;
+        self
+    }
+
+    /// The largest frame a client may send. Clients sending larger frames
+    /// are closed with `1009 MESSAGE_TOO_BIG`.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.config.limits.max_frame_size = Some(size);
+        self
+    }
+
+    /// The largest message a client may send, after reassembling its frames.
+    /// Clients sending larger messages are closed with `1009 MESSAGE_TOO_BIG`.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.limits.max_message_size = Some(size);
+        self
+    }
+
+    /// How many messages each client may send per second, counting pings
+    /// and pongs. Clients sending more are closed with `1008 TOO_MANY_MESSAGES`.
+    pub fn max_messages_per_second(mut self, count: usize) -> Self {
+        self.config.limits.max_messages_per_second = Some(count);
+        self
+    }
+
+    /// How many outgoing messages to queue per client. Defaults to 20.
+    pub fn outbound_queue(mut self, size: usize) -> Self {
+        self.config.outbound_queue = size;
+        self
+    }
+
+    /// What to do when a client's outbound queue is full.
+    /// Defaults to `SlowConsumer::Wait`, can be changed per client.
+    pub fn slow_consumer(mut self, policy: SlowConsumer) -> Self {
+        self.config.slow_consumer = policy;
+        self
+    }
+
+    /// How the messages of each client are ordered.
+    /// Defaults to `Ordering::Concurrent`.
+    pub fn ordering(mut self, ordering: Ordering) -> Self {
+        self.config.ordering = ordering;
+        self
+    }
+
+    /// Inspect the upgrade request before the client is connected,
+    /// eg. to check credentials or the origin.
+    pub fn on_upgrade<S>(mut self, service: S) -> Self
+    where
+        S: IntoService<Input = Handshake, Output = Admission, Error = JuntaError>,
+        <S as IntoService>::Service: 'static + Send + Sync,
+    {
+        let service = service.into_service();
+        self.on_upgrade = Some(Arc::new(move |handshake| {
+            Box::new(service.call(handshake)) as Box<Future<Item = _, Error = _> + Send>
+        }));
+        self
+    }
+
+    /// Answer plain HTTP requests to `path`, eg. `/healthz`, instead
+    /// of refusing them. Other requests which are not websocket
+    /// upgrades are answered with `404 Not Found`.
+    pub fn http<P, S>(mut self, path: P, service: S) -> Self
+    where
+        P: Into<String>,
+        S: IntoService<Input = HttpRequest, Output = HttpResponse, Error = JuntaError>,
+        <S as IntoService>::Service: 'static + Send + Sync,
+    {
+        let service = service.into_service();
+        let handler: HttpHandler = Arc::new(move |request| {
+            Box::new(service.call(request)) as Box<Future<Item = _, Error = _> + Send>
+        });
+        self.routes.insert(path.into(), handler);
+        self
+    }
+
+    /// Called when the handler fails. It can log, reply to the client
+    /// or close the connection. By default the error is logged.
+    ///
+    /// The hook is given the event the handler failed on, so with a hook
+    /// every message is copied before the handler runs, even if it succeeds.
+    /// This doubles the memory used by large messages, so prefer
+    /// `stream_binary` for those; a streamed message is not copied.
+    pub fn on_error<S>(mut self, service: S) -> Self
+    where
+        S: IntoService<Input = Context<HandlerError>, Output = (), Error = JuntaError>,
+        <S as IntoService>::Service: 'static + Send + Sync,
+    {
+        let service = service.into_service();
+        self.config.on_error = Some(Arc::new(move |ctx| {
+            Box::new(service.call(ctx)) as Box<Future<Item = _, Error = _> + Send>
+        }));
+        self
+    }
+
     pub fn serve<H>(self, executor: TaskExecutor, handler: H) -> JuntaResult<Server>
     where
         H: IntoService<Input = Context<ClientEvent>, Output = (), Error = JuntaError>,
         <H as IntoService>::Service: 'static + Send + Sync,
     {
-        let clients = Arc::new(RwLock::new(HashMap::new()));
+        self.serve_with_shutdown(executor, handler, futures::future::empty::<(), ()>())
+    }
+
+    /// Runs `handler` on in-memory connections instead of a socket,
+    /// with the same dispatch as `serve`. Useful for testing handlers.
+    pub fn serve_loopback<H>(self, executor: TaskExecutor, handler: H) -> Loopback
+    where
+        H: IntoService<Input = Context<ClientEvent>, Output = (), Error = JuntaError>,
+        <H as IntoService>::Service: 'static + Send + Sync,
+    {
+        Loopback::new(Dispatcher {
+            server: Arc::new(Broadcaster::new(executor.clone())),
+            logger: self.logger,
+            executor,
+            handler: Arc::new(handler.into_service()),
+            counter: Arc::new(atomic_counter::RelaxedCounter::new(1)),
+            inflight: channel(0).0,
+            config: self.config,
+        })
+    }
+
+    /// Like `serve`, but shuts down gracefully when `signal` resolves:
+    /// stops accepting connections, sends the shutdown close frame to every client
+    /// and waits up to the shutdown timeout for clients and handlers to finish.
+    pub fn serve_with_shutdown<H, F>(
+        self,
+        executor: TaskExecutor,
+        handler: H,
+        signal: F,
+    ) -> JuntaResult<Server>
+    where
+        H: IntoService<Input = Context<ClientEvent>, Output = (), Error = JuntaError>,
+        <H as IntoService>::Service: 'static + Send + Sync,
+        F: Future + Send + 'static,
+    {
         Ok(Server {
             inner: ServerHandler::new(
                 executor,
                 Arc::new(handler.into_service()),
-                clients,
-                self.logger,
-                self.addr,
+                self,
+                Box::new(signal.then(|_| Ok(()))),
             )?,
         })
     }
@@ -122,11 +602,56 @@ pub struct Server {
 }
 
 impl Server {
+    /// A handle for reaching the connected clients.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.inner.server.clone())
+    }
+
+    /// The addresses the TCP listeners are bound to, in the order they
+    /// were added. Useful to find the port picked when binding port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.inner.addrs
+    }
+
     pub fn bind<S: ToSocketAddrs>(addr: S) -> JuntaResult<ServerBuilder> {
-        Ok(ServerBuilder {
-            addr: addr.to_socket_addrs()?.nth(0).unwrap(),
+        Ok(Server::builder(vec![Listener::new(
+            Listen::Tcp(resolve(addr)?),
+            None,
+        )]))
+    }
+
+    /// Listen on a unix domain socket. A socket file left behind
+    /// by a server which is no longer running is replaced,
+    /// and the file is removed again when the server stops.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> JuntaResult<ServerBuilder> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Server::builder(vec![Listener::new(
+            Listen::Unix(path),
+            None,
+        )]))
+    }
+
+    /// A server without sockets, see `ServerBuilder::serve_loopback`.
+    pub fn loopback() -> ServerBuilder {
+        Server::builder(Vec::new())
+    }
+
+    fn builder(listeners: Vec<Listener>) -> ServerBuilder {
+        ServerBuilder {
+            listeners,
+            tls: None,
             logger: Logger::root(Discard, o! {}),
-        })
+            protocols: vec!["rust-websocket".to_string()],
+            allow_no_protocol: false,
+            deflate: None,
+            shutdown_close: CloseCode::GoingAway.with_reason("GOING_AWAY"),
+            shutdown_timeout: Duration::from_secs(5),
+            config: ClientConfig::default(),
+            on_upgrade: None,
+            routes: HashMap::new(),
+            limits: Limits::default(),
+        }
     }
 }
 
@@ -138,17 +663,248 @@ impl Future for Server {
     }
 }
 
-struct ServerHandler {
+/// Settings applied to every connection.
+#[derive(Clone)]
+pub(crate) struct ClientConfig {
+    pub(crate) heartbeat: Option<(Duration, Duration)>,
+    pub(crate) close_grace: Duration,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) idle_code: CloseCode,
+    pub(crate) outbound_queue: usize,
+    pub(crate) slow_consumer: SlowConsumer,
+    pub(crate) ordering: Ordering,
+    pub(crate) on_error: Option<ErrorHook>,
+    pub(crate) limits: MessageLimits,
+    pub(crate) stream_threshold: Option<usize>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            heartbeat: None,
+            close_grace: Duration::from_secs(1),
+            idle_timeout: None,
+            idle_code: CloseCode::GoingAway,
+            outbound_queue: 20,
+            slow_consumer: SlowConsumer::Wait,
+            ordering: Ordering::default(),
+            on_error: None,
+            limits: MessageLimits::default(),
+            stream_threshold: None,
+        }
+    }
+}
+
+/// Everything needed to run a connection.
+/// Every connection task and handler future holds a clone of `inflight`,
+/// so the server knows when they have all finished.
+pub(crate) struct Dispatcher<H> {
+    pub(crate) server: Arc<Broadcaster>,
+    pub(crate) logger: Logger,
+    pub(crate) executor: TaskExecutor,
+    pub(crate) handler: Arc<H>,
+    pub(crate) counter: Arc<atomic_counter::RelaxedCounter>,
+    pub(crate) inflight: Sender<()>,
+    pub(crate) config: ClientConfig,
+}
+
+impl<H> Clone for Dispatcher<H> {
+    fn clone(&self) -> Self {
+        Dispatcher {
+            server: self.server.clone(),
+            logger: self.logger.clone(),
+            executor: self.executor.clone(),
+            handler: self.handler.clone(),
+            counter: self.counter.clone(),
+            inflight: self.inflight.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+type UpgradeHook =
+    Arc<Fn(Handshake) -> Box<Future<Item = Admission, Error = JuntaError> + Send> + Send + Sync>;
+
+struct Acceptor<H> {
+    dispatcher: Dispatcher<H>,
+    protocols: Arc<Vec<String>>,
+    allow_no_protocol: bool,
+    deflate: Option<Deflate>,
+    on_upgrade: Option<UpgradeHook>,
+    limiter: Arc<Limiter>,
+    routes: Arc<HashMap<String, HttpHandler>>,
+}
+
+impl<H> Clone for Acceptor<H> {
+    fn clone(&self) -> Self {
+        Acceptor {
+            dispatcher: self.dispatcher.clone(),
+            protocols: self.protocols.clone(),
+            allow_no_protocol: self.allow_no_protocol,
+            deflate: self.deflate,
+            on_upgrade: self.on_upgrade.clone(),
+            limiter: self.limiter.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+impl<H> Acceptor<H>
+where
+    H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
+        + 'static
+        + Send
+        + Sync,
+{
+    /// Performs the websocket handshake on an accepted stream,
+    /// or answers it if it is a plain HTTP request
+    fn accept<S>(self, stream: S, addr: Address) -> impl Future<Item = (), Error = JuntaError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        stream.into_ws().then(move |ret| {
+            let fut = match ret {
+                Ok(upgrade) => OneOfTwo::First(self.upgrade(upgrade, addr)),
+                Err((stream, Some(request), _, _)) => {
+                    let server = ServerHandle::new(self.dispatcher.server.clone());
+                    let request = HttpRequest::new(Handshake::new(&request, addr), server);
+                    let handler = self.routes.get(request.path()).cloned();
+                    OneOfTwo::Second(OneOfTwoFuture::new(OneOfTwo::First(respond(
+                        stream,
+                        request,
+                        handler,
+                        self.dispatcher.logger.clone(),
+                    ))))
+                }
+                Err((_, None, _, error)) => {
+                    OneOfTwo::Second(OneOfTwoFuture::new(OneOfTwo::Second(futures::future::err(
+                        JuntaError::new(JuntaErrorKind::Transport(WebSocketError::from(error))),
+                    ))))
+                }
+            };
+            OneOfTwoFuture::new(fut)
+        })
+    }
+
+    fn upgrade<S>(
+        self,
+        upgrade: Upgrade<S>,
+        addr: Address,
+    ) -> impl Future<Item = (), Error = JuntaError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let permit = match self.limiter.admit(addr.ip()) {
+            Ok(permit) => permit,
+            Err((status, reason)) => {
+                self.dispatcher.server.rejected.inc();
+                warn!(self.dispatcher.logger, "connection rejected"; "address" => addr.to_string(), "status" => status, "reason" => reason);
+                self.dispatcher.executor.spawn(
+                    tokio::io::write_all(upgrade.stream, rejection(status, reason))
+                        .map(|_| ())
+                        .map_err(|_| ()),
+                );
+                return OneOfTwoFuture::new(OneOfTwo::First(futures::future::ok(())));
+            }
+        };
+
+        let protocol = self
+            .protocols
+            .iter()
+            .find(|p| upgrade.protocols().iter().any(|s| s == *p))
+            .cloned();
+
+        if protocol.is_none() && (!self.allow_no_protocol || !upgrade.protocols().is_empty()) {
+            debug!(self.dispatcher.logger, "no acceptable subprotocol"; "protocols" => format!("{:?}", upgrade.protocols()));
+            self.dispatcher
+                .executor
+                .spawn(upgrade.reject().map(|_| ()).map_err(|_| ()));
+            return OneOfTwoFuture::new(OneOfTwo::First(futures::future::ok(())));
+        }
+
+        let upgrade = match &protocol {
+            Some(protocol) => upgrade.use_protocol(protocol.as_str()),
+            None => upgrade,
+        };
+
+        let offers: Vec<String> = upgrade
+            .request
+            .headers
+            .get_raw(EXTENSIONS)
+            .unwrap_or_default()
+            .iter()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .collect();
+        let deflate = self.deflate.and_then(|deflate| deflate.negotiate(&offers));
+        let mut headers = Headers::new();
+        if let Some((_, response)) = &deflate {
+            headers.set_raw(EXTENSIONS, vec![response.clone().into_bytes()]);
+        }
+
+        let logger = self.dispatcher.logger.clone();
+        let admission = match &self.on_upgrade {
+            Some(hook) => {
+                OneOfTwo::First(hook(Handshake::new(&upgrade.request, addr.clone())).then(
+                    move |ret| -> JuntaResult<Result<ShareMap, (u16, String)>> {
+                        Ok(match ret {
+                            Ok(Admission::Accept(handshake)) => Ok(handshake.into_extensions()),
+                            Ok(Admission::Reject(status, body)) => Err((status, body)),
+                            Err(e) => {
+                                warn!(logger, "upgrade hook failed"; "error" => e.to_string());
+                                Err((500, String::new()))
+                            }
+                        })
+                    },
+                ))
+            }
+            None => OneOfTwo::Second(futures::future::ok(Ok(TypeMap::custom()))),
+        };
+
+        let dispatcher = self.dispatcher;
+        let fut = OneOfTwoFuture::new(admission).and_then(move |admission| {
+            let fut = match admission {
+                Ok(data) => OneOfTwo::First(
+                    upgrade
+                        .accept_with(&headers)
+                        .map_err(|e| JuntaError::new(JuntaErrorKind::Transport(e)))
+                        .map(move |(client, _)| {
+                            let codec = Codec::new(MsgCodecCtx::Server, dispatcher.config.limits)
+                                .deflate(
+                                    deflate.map(|(deflate, _)| DeflateContext::server(&deflate)),
+                                )
+                                .stream_binary(dispatcher.config.stream_threshold);
+                            let client = Codec::wrap(client, codec);
+                            dispatcher.connect(client, addr, protocol, data, Some(permit));
+                        }),
+                ),
+                Err((status, body)) => {
+                    debug!(dispatcher.logger, "upgrade rejected"; "status" => status);
+                    OneOfTwo::Second(
+                        tokio::io::write_all(upgrade.stream, rejection(status, &body))
+                            .map(|_| ())
+                            .map_err(JuntaError::from),
+                    )
+                }
+            };
+            OneOfTwoFuture::new(fut)
+        });
+
+        OneOfTwoFuture::new(OneOfTwo::Second(fut))
+    }
+}
+
+pub(crate) struct ServerHandler {
     inner: Box<Future<Item = (), Error = JuntaError> + Send>,
+    server: Arc<Broadcaster>,
+    addrs: Vec<SocketAddr>,
 }
 
 impl ServerHandler {
     pub fn new<H: Service + 'static>(
         executor: TaskExecutor,
         handler: Arc<H>,
-        clients: ClientList,
-        logger: Logger,
-        addr: SocketAddr,
+        builder: ServerBuilder,
+        signal: Box<Future<Item = (), Error = JuntaError> + Send>,
     ) -> JuntaResult<ServerHandler>
     where
         H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
@@ -156,90 +912,181 @@ impl ServerHandler {
             + Send
             + Sync,
     {
-        let counter = Arc::new(atomic_counter::RelaxedCounter::new(1));
+        let logger = builder.logger;
+        let server = Arc::new(Broadcaster::new(executor.clone()));
+        let handle = server.clone();
+        let (inflight, drained) = channel(0);
 
-        let work = WSServer::bind(addr, &Handle::default())?
-            .incoming()
-            .map_err(|InvalidConnection { error, .. }| {
-                JuntaError::new(JuntaErrorKind::Transport(WebSocketError::from(error)))
-            })
-            .from_err::<JuntaError>()
-            .for_each(move |(upgrade, addr)| {
-                let fut = if !upgrade.protocols().iter().any(|s| s == "rust-websocket") {
-                    executor.spawn(upgrade.reject().map(|_| ()).map_err(|_| ()));
-                    OneOfTwo::First(futures::future::ok(()))
-                } else {
-                    let t = executor.clone();
-
-                    let clients = clients.clone();
-                    let logger = logger.clone();
-                    let handler = handler.clone();
-                    let counter = counter.clone();
+        let acceptor = Acceptor {
+            dispatcher: Dispatcher {
+                server: server.clone(),
+                logger: logger.clone(),
+                executor: executor.clone(),
+                handler,
+                counter: Arc::new(atomic_counter::RelaxedCounter::new(1)),
+                inflight,
+                config: builder.config,
+            },
+            protocols: Arc::new(builder.protocols),
+            allow_no_protocol: builder.allow_no_protocol,
+            deflate: builder.deflate,
+            on_upgrade: builder.on_upgrade,
+            limiter: Limiter::new(builder.limits),
+            routes: Arc::new(builder.routes),
+        };
 
-                    OneOfTwo::Second(
-                        upgrade
-                            .use_protocol("rust-websocket")
-                            .accept()
-                            .map_err(|e| JuntaError::new(JuntaErrorKind::Transport(e)))
-                            .and_then(move |(client, _)| {
-                                ServerHandler::connect(
-                                    clients, logger, t, handler, client, addr, counter,
-                                )
-                            }),
-                    )
-                };
+        let mut listeners = Vec::with_capacity(builder.listeners.len());
+        let mut addrs = Vec::new();
+        let shared = match builder.tls {
+            Some(identity) => Some(identity.acceptor()?),
+            None => None,
+        };
+        for listener in builder.listeners {
+            let tls = match (listener.tls, &listener.listen) {
+                (Some(identity), _) => Some(identity.acceptor()?),
+                (None, Listen::Tcp(_)) => shared.clone(),
+                #[cfg(unix)]
+                (None, Listen::Unix(_)) => None,
+            };
+            let (acceptor, executor, logger) = (acceptor.clone(), executor.clone(), logger.clone());
+            listeners.push(match listener.listen {
+                Listen::Tcp(addr) => {
+                    let listener = TcpListener::bind(&addr)?;
+                    addrs.push(listener.local_addr()?);
+                    accept_from(listener.incoming(), tls, acceptor, executor, logger)
+                }
+                #[cfg(unix)]
+                Listen::Unix(path) => {
+                    remove_stale(&path)?;
+                    let incoming = UnixListener::bind(&path)?.incoming();
+                    let file = SocketFile(path);
+                    // Dropped along with the listener, also on shutdown
+                    Box::new(accept_from(incoming, tls, acceptor, executor, logger).then(
+                        move |ret| {
+                            drop(file);
+                            ret
+                        },
+                    ))
+                }
+            });
+        }
+        // Stops accepting everywhere as soon as one listener fails
+        let accept = futures::future::join_all(listeners).map(|_| ());
 
+        let close = builder.shutdown_close;
+        let timeout = builder.shutdown_timeout;
+        let work = accept
+            .select2(signal)
+            .map_err(|e| e.split().0)
+            .and_then(move |done| {
+                let fut = match done {
+                    Either::A(_) => OneOfTwo::First(futures::future::ok(())),
+                    Either::B((_, accept)) => {
+                        // Dropping the listener also drops its handle on `inflight`.
+                        drop(accept);
+                        close_all(&server, &logger, close);
+                        OneOfTwo::Second(drained.into_future().timeout(timeout).then(move |ret| {
+                            match ret {
+                                Ok(_) => info!(logger, "shutdown complete"),
+                                Err(_) => warn!(logger, "shutdown timed out"),
+                            };
+                            Ok(())
+                        }))
+                    }
+                };
                 OneOfTwoFuture::new(fut)
             });
+
         Ok(ServerHandler {
             inner: Box::new(work),
+            server: handle,
+            addrs,
         })
     }
+}
 
-    fn connect<H: Service + 'static>(
-        clients: ClientList,
-        logger: Logger,
-        executor: TaskExecutor,
-        handler: Arc<H>,
-        client: tokio::codec::Framed<
-            tokio::net::TcpStream,
-            websocket::r#async::MessageCodec<websocket::OwnedMessage>,
-        >,
-        addr: SocketAddr,
-        counter: Arc<atomic_counter::RelaxedCounter>,
-    ) -> impl Future<Item = (), Error = JuntaError>
+/// The first address `addr` resolves to.
+fn resolve<S: ToSocketAddrs>(addr: S) -> JuntaResult<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| JuntaErrorKind::InvalidAddress.into())
+}
+
+fn lane(ordering: &Ordering, msg: &MessageContent) -> Lane {
+    match ordering {
+        Ordering::Sequential => Lane::Inline,
+        Ordering::Concurrent => Lane::Spawn,
+        Ordering::Keyed(key) => Lane::Keyed(key(msg)),
+    }
+}
+
+fn close_all(server: &Broadcaster, logger: &Logger, close: CloseData) {
+    // Set before collecting, so clients finishing their handshake see one or the other
+    *server.shutdown.write().unwrap() = Some(close.clone());
+    let clients: Vec<_> = server.clients.read().unwrap().values().cloned().collect();
+    info!(logger, "shutting down"; "clients" => clients.len());
+    for client in clients {
+        if let Err(e) = client.send_close(close.clone()) {
+            warn!(client.logger(), "could not send close"; "error" => e.to_string());
+        }
+    }
+}
+
+impl<H> Dispatcher<H>
+where
+    H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
+        + 'static
+        + Send
+        + Sync,
+{
+    pub(crate) fn connect<S>(
+        &self,
+        client: Framed<S, Codec>,
+        addr: Address,
+        protocol: Option<String>,
+        data: ShareMap,
+        permit: Option<Permit>,
+    ) -> Arc<Client>
     where
-        H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
-            + 'static
-            + Send
-            + Sync,
+        S: AsyncRead + AsyncWrite + Send + 'static,
     {
         let id = Uuid::new_v4();
+        let (server, executor, handler) = (
+            self.server.clone(),
+            self.executor.clone(),
+            self.handler.clone(),
+        );
 
-        let logger = logger.new(slog::o! {
+        let logger = self.logger.new(slog::o! {
             "client" => id.to_string(),
-            "address" => format!("{:?}",client.get_ref().peer_addr().unwrap())
+            "address" => addr.to_string(),
+            "protocol" => protocol.clone().unwrap_or_default()
         });
 
         info!(logger, "client connected");
+        server.connected.inc();
+        let metrics = server.metrics.clone();
+        metrics.connected();
 
         let (sink, stream) = client.split();
 
         let (sx, rx) = futures::sync::mpsc::channel(20);
-        let (sx1, rx1) = futures::sync::mpsc::channel(20);
-        let (sx2, rx2) = futures::sync::oneshot::channel();
+        let outbox = Outbox::new(self.config.outbound_queue);
 
+        let clients = server.clients.clone();
         let client = Arc::new(Client {
             id: id.clone(),
-            sender: sx1,
-            server: Arc::new(Broadcaster {
-                clients: clients.clone(),
-                executor: executor.clone(),
-            }),
+            outbox: outbox.clone(),
+            slow_consumer: Mutex::new(self.config.slow_consumer),
+            server: server.clone(),
             address: addr,
-            counter: counter,
+            counter: self.counter.clone(),
+            protocol,
+            upgrade_data: data,
+            session: RwLock::new(TypeMap::custom()),
             logger: logger.clone(),
-            close: Mutex::new(Some(sx2)),
+            metrics: metrics.clone(),
+            idle: Idle::new(self.config.idle_timeout),
         });
 
         let (cloned_client, cloned_list, cloned_handler) =
@@ -248,75 +1095,153 @@ impl ServerHandler {
         let cl = client.clone();
         //let this2 = this.clone();
 
-        clients.write().unwrap().insert(id.clone(), client);
+        clients.write().unwrap().insert(id.clone(), client.clone());
+        // The server started shutting down during the handshake
+        if let Some(close) = server.shutdown.read().unwrap().clone() {
+            client.send_close(close).ok();
+        }
+
         let exec = executor.clone();
         let logger = logger.clone();
-        let v = handler
-            .call(Context::<ClientEvent>::new(
-                cl.clone(),
-                ClientEvent::Connect,
-            ))
+        let inflight = self.inflight.clone();
+        let ordering = self.config.ordering.clone();
+        let on_error = self.config.on_error.clone();
+        let connect_error = on_error.clone();
+        let connect_client = cl.clone();
+        let mut tails = HashMap::new();
+        let timer = metrics.clone();
+        // Disconnects are counted by the peer's close frame if there is one
+        let peer_closed = Arc::new(AtomicBool::new(false));
+        let closing = peer_closed.clone();
+        let v = metrics
+            .time("connect", || {
+                handler.call(Context::<ClientEvent>::new(
+                    cl.clone(),
+                    ClientEvent::Connect,
+                ))
+            })
+            .or_else(move |e| {
+                let event = keep(&connect_error, &ClientEvent::Connect);
+                report(&connect_error, connect_client, event, e).then(|_| Ok(()))
+            })
             .and_then(|_| {
                 rx.map_err(|_| JuntaError::from(ServiceError::ReceiverClosed))
                     .for_each(move |msg| {
                         let cl = cl.clone();
-                        let fut = match msg {
-                            OwnedMessage::Close(close_data) => {
+                        // With `Ordering::Keyed` the handler is only called once the key's
+                        // last message is done, so even its synchronous part runs in order
+                        let call = |ctx, lane: &Lane| {
+                            let (handler, timer) = (handler.clone(), timer.clone());
+                            match lane {
+                                Lane::Keyed(_) => Either::A(futures::future::lazy(move || {
+                                    timer.time("message", || handler.call(ctx))
+                                })),
+                                _ => Either::B(timer.time("message", || handler.call(ctx))),
+                            }
+                        };
+                        let (fut, lane, event) = match msg {
+                            Incoming::Message(OwnedMessage::Close(close_data)) => {
                                 clients.write().unwrap().remove(&cl.id);
                                 debug!(logger, "client sent close message");
+                                timer.disconnected(close_data.as_ref().map(|c| c.status_code));
+                                closing.store(true, AtomicOrdering::SeqCst);
                                 let client = cl.clone();
                                 let logger = logger.clone();
-                                let out = handler
-                                    .call(Context::<ClientEvent>::new(
-                                        cl,
-                                        ClientEvent::Close(close_data),
-                                    ))
+                                let event = ClientEvent::Close(close_data);
+                                let kept = keep(&on_error, &event);
+                                let out = timer
+                                    .time("close", || {
+                                        handler.call(Context::<ClientEvent>::new(cl.clone(), event))
+                                    })
                                     .and_then(move |_| {
                                         debug!(logger, "sending close to client");
                                         client.close()
                                     });
 
-                                OneOfFour::First(out)
+                                let lane = match ordering {
+                                    Ordering::Sequential => Lane::Inline,
+                                    _ => Lane::Spawn,
+                                };
+                                (OneOfFour::First(out), lane, kept)
                             }
-                            OwnedMessage::Ping(ping) => {
+                            Incoming::Message(OwnedMessage::Ping(ping)) => {
                                 debug!(logger, "client sent ping");
-                                OneOfFour::Second(
-                                    cl.sender
-                                        .clone()
-                                        .send(OwnedMessage::Pong(ping))
-                                        .map(|_| ())
-                                        .map_err(|_| JuntaErrorKind::Send.into()),
-                                )
+                                let out = futures::future::result(
+                                    cl.outbox.push_control(OwnedMessage::Pong(ping)),
+                                );
+                                (OneOfFour::Second(out), Lane::Spawn, None)
+                            }
+                            Incoming::Message(OwnedMessage::Pong(_)) => {
+                                (OneOfFour::Third(futures::future::ok(())), Lane::Spawn, None)
                             }
-                            OwnedMessage::Pong(_) => OneOfFour::Third(futures::future::ok(())),
-                            OwnedMessage::Binary(data) => {
+                            Incoming::Message(OwnedMessage::Binary(data)) => {
                                 debug!(logger, "client sent binary message");
-                                OneOfFour::Fourth(handler.call(Context::<ClientEvent>::new(
-                                    cl,
-                                    ClientEvent::Message(MessageContent::Binary(data)),
-                                )))
+                                let msg = MessageContent::Binary(data);
+                                timer.received(&msg);
+                                let lane = lane(&ordering, &msg);
+                                let event = ClientEvent::Message(msg);
+                                let kept = keep(&on_error, &event);
+                                let out =
+                                    call(Context::<ClientEvent>::new(cl.clone(), event), &lane);
+                                (OneOfFour::Fourth(out), lane, kept)
                             }
-                            OwnedMessage::Text(data) => {
+                            Incoming::Message(OwnedMessage::Text(data)) => {
                                 debug!(logger, "client sent text message");
-                                OneOfFour::Fourth(handler.call(Context::<ClientEvent>::new(
-                                    cl,
-                                    ClientEvent::Message(MessageContent::Text(data)),
-                                )))
+                                let msg = MessageContent::Text(data);
+                                timer.received(&msg);
+                                let lane = lane(&ordering, &msg);
+                                let event = ClientEvent::Message(msg);
+                                let kept = keep(&on_error, &event);
+                                let out =
+                                    call(Context::<ClientEvent>::new(cl.clone(), event), &lane);
+                                (OneOfFour::Fourth(out), lane, kept)
+                            }
+                            Incoming::Stream(stream) => {
+                                debug!(logger, "client started a streamed binary message");
+                                let lane = match ordering {
+                                    Ordering::Sequential => Lane::Inline,
+                                    _ => Lane::Spawn,
+                                };
+                                let event = ClientEvent::Stream(TakeStream::new(stream));
+                                let kept = keep(&on_error, &event);
+                                let out =
+                                    call(Context::<ClientEvent>::new(cl.clone(), event), &lane);
+                                (OneOfFour::Fourth(out), lane, kept)
                             }
                         };
 
-                        exec.spawn(
-                            OneOfFourFuture::new(fut)
-                                // .or_else(|e| {
-                                //     println!("TOP-error {}", e);
-                                //     Ok(())
-                                // })
-                                .map_err(|e: JuntaError| {
-                                    println!("error {}", e);
-                                    ()
-                                }),
-                        );
-                        futures::future::ok(())
+                        let inflight = inflight.clone();
+                        let on_error = on_error.clone();
+                        let fut = OneOfFourFuture::new(fut)
+                            .or_else(move |e| report(&on_error, cl, event, e))
+                            .then(move |ret| {
+                                drop(inflight);
+                                ret
+                            });
+
+                        match lane {
+                            Lane::Spawn => exec.spawn(fut),
+                            Lane::Inline => {
+                                return OneOfTwoFuture::new(OneOfTwo::First(fut.then(|_| Ok(()))));
+                            }
+                            Lane::Keyed(key) => {
+                                // Forget keys whose last message is done
+                                tails.retain(|_, tail: &mut OneReceiver<()>| match tail.poll() {
+                                    Ok(Async::NotReady) => true,
+                                    _ => false,
+                                });
+                                let (done, tail) = futures::sync::oneshot::channel();
+                                let fut = match tails.insert(key, tail) {
+                                    Some(prev) => OneOfTwo::First(prev.then(|_| fut)),
+                                    None => OneOfTwo::Second(fut),
+                                };
+                                exec.spawn(OneOfTwoFuture::new(fut).then(move |ret| {
+                                    done.send(()).ok();
+                                    ret
+                                }));
+                            }
+                        };
+                        OneOfTwoFuture::new(OneOfTwo::Second(futures::future::ok(())))
                         // OneOfFourFuture::new(fut).map_err(|e: JuntaError| {
                         //     //println!("error {}", e);
                         //     e
@@ -324,36 +1249,130 @@ impl ServerHandler {
                     })
             });
 
-        let clogger = cloned_client.logger().clone();
-        let fut = ClientFuture::new(sink, stream, sx, rx1, rx2);
+        let fut = ClientFuture::new(sink, stream, sx, OutboxReader::new(outbox))
+            .close_grace(self.config.close_grace)
+            .idle_timeout(cloned_client.idle.clone(), self.config.idle_code)
+            .metrics(cloned_client.metrics.clone());
+        let fut = match self.config.heartbeat {
+            Some((interval, timeout)) => fut.heartbeat(interval, timeout),
+            None => fut,
+        };
+        // The socket is closed by now, make room before the close handler runs
+        let fut = fut.then(move |ret| {
+            drop(permit);
+            ret
+        });
         let client = cloned_client.clone();
+        let out = cloned_client.clone();
+        let finished = cloned_client.clone();
+        let inflight = self.inflight.clone();
+        let on_error = self.config.on_error.clone();
+        let errored = peer_closed.clone();
         executor.spawn(
             v.join(fut)
-                .and_then(move |_| {
-                    let logger = cloned_client.logger().clone();
-                    let elogger = logger.clone();
+                .and_then(move |(_, reason)| {
+                    if !peer_closed.load(AtomicOrdering::SeqCst) {
+                        metrics.disconnected(reason.as_ref().map(|r| r.status_code));
+                    }
                     cloned_list.write().unwrap().remove(cloned_client.id());
                     let client = cloned_client.clone();
-                    cloned_handler
-                        .call(Context::<ClientEvent>::new(
-                            cloned_client,
-                            ClientEvent::Close(None),
-                        ))
+                    let failed = cloned_client.clone();
+                    let event = ClientEvent::Close(reason);
+                    let kept = keep(&on_error, &event);
+                    cloned_client
+                        .metrics
+                        .clone()
+                        .time("close", || {
+                            cloned_handler.call(Context::<ClientEvent>::new(cloned_client, event))
+                        })
                         .map(move |_| {
                             info!(client.logger(), "client closed");
                             ()
                         })
-                        .map_err(move |e| {
-                            error!(elogger, "client closed with error"; "error" => e.to_string());
-                            e
-                        })
+                        .or_else(move |e| report(&on_error, failed, kept, e).then(|_| Ok(())))
                 })
                 .map_err(move |e| {
+                    if !errored.load(AtomicOrdering::SeqCst) {
+                        client.metrics.disconnected(None);
+                    }
                     error!(client.logger(), "client finished with error {}", e);
                     ()
+                })
+                .then(move |ret| {
+                    server.leave_all(finished.id());
+                    finished.session_mut().clear();
+                    drop(inflight);
+                    ret
                 }),
         );
-        futures::future::ok(())
+        out
+    }
+}
+
+/// Accepts connections from `incoming` until it ends.
+fn accept_from<I, S, H>(
+    incoming: I,
+    tls: Option<TlsAcceptor>,
+    acceptor: Acceptor<H>,
+    executor: TaskExecutor,
+    logger: Logger,
+) -> Box<Future<Item = (), Error = JuntaError> + Send>
+where
+    I: Stream<Item = S, Error = io::Error> + Send + 'static,
+    S: Peer + AsyncRead + AsyncWrite + Send + 'static,
+    H: Service<Input = Context<ClientEvent>, Output = (), Error = JuntaError>
+        + 'static
+        + Send
+        + Sync,
+{
+    Box::new(incoming.map_err(JuntaError::from).for_each(move |stream| {
+        let addr = match stream.peer() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!(logger, "could not read peer address"; "error" => e.to_string());
+                return Ok(());
+            }
+        };
+        let acceptor = acceptor.clone();
+        let fut = match &tls {
+            Some(tls) => OneOfTwo::First(
+                tls.accept(stream)
+                    .map_err(JuntaError::from)
+                    .and_then(move |stream| acceptor.accept(stream, addr)),
+            ),
+            None => OneOfTwo::Second(acceptor.accept(stream, addr)),
+        };
+        let logger = logger.clone();
+        executor.spawn(OneOfTwoFuture::new(fut).map_err(move |e| {
+            warn!(logger, "handshake failed"; "error" => e.to_string());
+        }));
+        Ok(())
+    }))
+}
+
+/// Removes a unix socket file when dropped.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Removes a socket file left behind by a server which is no longer running.
+#[cfg(unix)]
+fn remove_stale(path: &Path) -> JuntaResult<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use").into()),
+                Err(_) => Ok(fs::remove_file(path)?),
+            }
+        }
+        _ => Ok(()),
     }
 }
 
@@ -364,3 +1383,624 @@ impl Future for ServerHandler {
         self.inner.poll()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::plugins::Extensible;
+    use crate::prelude::*;
+    use futures::sync::{mpsc, oneshot};
+    use junta_service::prelude::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+    use websocket::{CloseData, OwnedMessage};
+
+    /// Runs `server` in the background, returning the address of its first
+    /// listener. Tests bind port 0, so they never fight over a port.
+    pub(crate) fn spawn(runtime: &mut Runtime, server: Server) -> SocketAddr {
+        let addr = server.local_addrs()[0];
+        runtime.spawn(server.map_err(|_| ()));
+        addr
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (signal, shutdown) = oneshot::channel::<()>();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .shutdown_close(CloseCode::GoingAway, "BYE")
+            .serve_with_shutdown(runtime.executor(), service_fn(|_| Ok(())), shutdown)
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        let (done, stopped) = oneshot::channel();
+        runtime.spawn(server.then(|ret| done.send(ret.is_ok()).map_err(|_| ())));
+
+        let (sx, rx) = mpsc::unbounded();
+        let _client = runtime
+            .block_on(Client::connect(format!("ws://{}", addr)).unwrap().serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if let ClientEvent::Close(Some(data)) = ctx.message() {
+                        sx.unbounded_send((data.status_code, data.reason.clone()))
+                            .unwrap();
+                    }
+                    Ok(())
+                }),
+            ))
+            .unwrap();
+
+        signal.send(()).unwrap();
+        let (close, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(close, Some((1001, "BYE".to_string())));
+        assert_eq!(runtime.block_on(stopped), Ok(true));
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .heartbeat(Duration::from_millis(20), Duration::from_millis(60))
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if let ClientEvent::Close(Some(data)) = ctx.message() {
+                        sx.unbounded_send((data.status_code, data.reason.clone()))
+                            .unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        // A peer which never answers pings
+        let _peer = runtime
+            .block_on(
+                websocket::ClientBuilder::new(&format!("ws://{}", addr))
+                    .unwrap()
+                    .add_protocol("rust-websocket")
+                    .async_connect_insecure(),
+            )
+            .unwrap();
+
+        let (reason, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(reason, Some((1006, HEARTBEAT_TIMEOUT.to_string())));
+    }
+
+    #[test]
+    fn test_close_grace_period() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .close_grace_period(Duration::from_millis(500))
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    let close = ctx.client().close_with(CloseCode::Application(4001), "BYE");
+                    if !ctx.message().is_connect() {
+                        sx.unbounded_send(ctx.into_message()).unwrap();
+                    }
+                    close
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        // A peer which never answers the close frame
+        let (peer, _) = runtime
+            .block_on(
+                websocket::ClientBuilder::new(&format!("ws://{}", addr))
+                    .unwrap()
+                    .add_protocol("rust-websocket")
+                    .async_connect_insecure(),
+            )
+            .unwrap();
+        let (msg, peer) = runtime.block_on(peer.into_future()).ok().unwrap();
+        match msg {
+            Some(OwnedMessage::Close(Some(data))) => {
+                assert_eq!(
+                    CloseCode::from(data.status_code),
+                    CloseCode::Application(4001)
+                )
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        let _peer = runtime
+            .block_on(peer.send(OwnedMessage::Text("late".to_string())))
+            .unwrap();
+
+        // Still read during the grace period, then closed without the peer's code
+        let events: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ClientEvent::Message(MessageContent::Text("late".to_string())),
+                ClientEvent::Close(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .idle_timeout(Duration::from_millis(100))
+            .idle_close_code(CloseCode::Application(4002))
+            .close_grace_period(Duration::from_millis(0))
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    match ctx.message() {
+                        ClientEvent::Message(_) => ctx
+                            .client()
+                            .set_idle_timeout(Some(Duration::from_millis(500))),
+                        ClientEvent::Close(Some(data)) => sx
+                            .unbounded_send((
+                                ctx.client().idle_timeout(),
+                                data.status_code,
+                                data.reason.clone(),
+                            ))
+                            .unwrap(),
+                        _ => {}
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let connect = || {
+            websocket::ClientBuilder::new(&format!("ws://{}", addr))
+                .unwrap()
+                .add_protocol("rust-websocket")
+                .async_connect_insecure()
+                .map(|(peer, _)| peer)
+        };
+        let _idle = runtime.block_on(connect()).unwrap();
+        let _busy = runtime
+            .block_on(connect().and_then(|peer| peer.send(OwnedMessage::Text("hi".to_string()))))
+            .unwrap();
+
+        // The idle client goes first, the busy one once its longer timeout passes
+        let closed: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            closed,
+            vec![
+                (
+                    Some(Duration::from_millis(100)),
+                    4002,
+                    IDLE_TIMEOUT.to_string()
+                ),
+                (
+                    Some(Duration::from_millis(500)),
+                    4002,
+                    IDLE_TIMEOUT.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    match ctx.message() {
+                        ClientEvent::Connect => ctx.client().join("chat"),
+                        ClientEvent::Message(msg) => {
+                            sx.unbounded_send(ctx.client().rooms()).unwrap();
+                            return ctx.client().broadcast_room("chat", msg.clone());
+                        }
+                        _ => {}
+                    }
+                    Box::new(futures::future::ok(()))
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let (sx2, rx2) = mpsc::unbounded();
+        let executor = runtime.executor();
+        let connect = |sx: Option<mpsc::UnboundedSender<MessageContent>>| {
+            Client::connect(format!("ws://{}", addr)).unwrap().serve(
+                executor.clone(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if let (Some(sx), ClientEvent::Message(msg)) = (&sx, ctx.message()) {
+                        sx.unbounded_send(msg.clone()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+        };
+        let _listener = runtime.block_on(connect(Some(sx2))).unwrap();
+        let sender = runtime.block_on(connect(None)).unwrap();
+        runtime
+            .block_on(sender.send(MessageContent::Text("Hello".to_string())))
+            .unwrap();
+
+        let (rooms, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(rooms, Some(vec!["chat".to_string()]));
+        let (msg, _) = runtime.block_on(rx2.into_future()).ok().unwrap();
+        assert_eq!(msg, Some(MessageContent::Text("Hello".to_string())));
+    }
+
+    struct User;
+
+    impl Key for User {
+        type Value = String;
+    }
+
+    #[test]
+    fn test_on_upgrade() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .on_upgrade(service_fn(|mut handshake: Handshake| {
+                if handshake.query() != Some("token=secret") {
+                    return Ok(handshake.reject(401, "invalid token"));
+                }
+                handshake
+                    .extensions_mut()
+                    .insert::<User>("rasmus".to_string());
+                Ok(handshake.accept())
+            }))
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if ctx.message().is_connect() {
+                        let user = ctx.client().upgrade_data().get::<User>().cloned();
+                        sx.unbounded_send(user).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let executor = runtime.executor();
+        let connect = |url: &str| {
+            Client::connect(url)
+                .unwrap()
+                .serve(executor.clone(), service_fn(|_| Ok(())))
+        };
+        assert!(runtime
+            .block_on(connect(&format!("ws://{}/?token=wrong", addr)))
+            .is_err());
+        runtime
+            .block_on(connect(&format!("ws://{}/?token=secret", addr)))
+            .unwrap();
+
+        let (user, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(user, Some(Some("rasmus".to_string())));
+    }
+
+    #[test]
+    fn test_subprotocols() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let serve = |runtime: &mut tokio::runtime::Runtime, allow_no_protocol| {
+            let (sx, rx) = mpsc::unbounded();
+            let server = Server::bind("127.0.0.1:0")
+                .unwrap()
+                .protocols(&["v2", "v1"])
+                .allow_no_protocol(allow_no_protocol)
+                .serve(
+                    runtime.executor(),
+                    service_fn(move |ctx: Context<ClientEvent>| {
+                        if ctx.message().is_connect() {
+                            let protocol = ctx.client().protocol().map(str::to_string);
+                            sx.unbounded_send(protocol).unwrap();
+                        }
+                        Ok(())
+                    }),
+                )
+                .unwrap();
+            (spawn(runtime, server), rx)
+        };
+        let connect = |runtime: &mut tokio::runtime::Runtime, addr, protocols: &[&str]| {
+            let connector = Client::connect(format!("ws://{}", addr))
+                .unwrap()
+                .protocols(protocols);
+            runtime.block_on(connector.serve(runtime.executor(), service_fn(|_| Ok(()))))
+        };
+
+        let (addr, rx) = serve(&mut runtime, false);
+        // The server's preference wins over the client's order
+        let client = connect(&mut runtime, addr, &["v1", "v2"]).unwrap();
+        assert_eq!(client.protocol(), Some("v2"));
+        let client = connect(&mut runtime, addr, &["v3", "v1"]).unwrap();
+        assert_eq!(client.protocol(), Some("v1"));
+        let accepted: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            accepted,
+            vec![Some("v2".to_string()), Some("v1".to_string())]
+        );
+
+        assert!(connect(&mut runtime, addr, &["v3"]).is_err());
+        assert!(connect(&mut runtime, addr, &[]).is_err());
+
+        let (addr, rx) = serve(&mut runtime, true);
+        let client = connect(&mut runtime, addr, &[]).unwrap();
+        assert_eq!(client.protocol(), None);
+        // Offering protocols, none of which match, is still refused
+        assert!(connect(&mut runtime, addr, &["v3"]).is_err());
+        let (accepted, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(accepted, Some(None));
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .max_connections_per_ip(1)
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if ctx.message().is_close() {
+                        sx.unbounded_send(()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let handle = server.handle();
+        let addr = spawn(&mut runtime, server);
+
+        let executor = runtime.executor();
+        let connect = || {
+            Client::connect(format!("ws://{}", addr))
+                .unwrap()
+                .serve(executor.clone(), service_fn(|_| Ok(())))
+        };
+        let first = runtime.block_on(connect()).unwrap();
+        assert!(runtime.block_on(connect()).is_err());
+        assert_eq!(handle.rejected_connections(), 1);
+
+        // The room is given back before the close handler runs
+        runtime.block_on(first.close()).unwrap();
+        runtime.block_on(rx.into_future()).ok().unwrap();
+        runtime.block_on(connect()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let path = std::env::temp_dir().join("junta-test.sock");
+        // Left behind by a server which is gone
+        std::fs::remove_file(&path).ok();
+        std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let (signal, shutdown) = oneshot::channel::<()>();
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind_unix(&path)
+            .unwrap()
+            .serve_with_shutdown(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if ctx.message().is_connect() {
+                        sx.unbounded_send(ctx.client().address().clone()).unwrap();
+                    }
+                    Ok(())
+                }),
+                shutdown,
+            )
+            .unwrap();
+        let (done, stopped) = oneshot::channel();
+        runtime.spawn(server.then(|ret| done.send(ret.is_ok()).map_err(|_| ())));
+
+        let (client, _) = runtime
+            .block_on(
+                tokio::net::UnixStream::connect(&path)
+                    .map_err(websocket::WebSocketError::from)
+                    .and_then(|stream| {
+                        websocket::ClientBuilder::new("ws://localhost/")
+                            .unwrap()
+                            .add_protocol("rust-websocket")
+                            .async_connect_on(stream)
+                    }),
+            )
+            .unwrap();
+        let (address, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert!(address.unwrap().is_unix());
+
+        drop(client);
+        signal.send(()).unwrap();
+        assert_eq!(runtime.block_on(stopped), Ok(true));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .listen("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(|ctx: Context<ClientEvent>| {
+                    let fut: Box<Future<Item = (), Error = JuntaError> + Send> = match ctx.message()
+                    {
+                        ClientEvent::Message(msg) => Box::new(ctx.client().broadcast(msg.clone())),
+                        _ => Box::new(futures::future::ok(())),
+                    };
+                    fut
+                }),
+            )
+            .unwrap();
+        let handle = server.handle();
+        let addrs = server.local_addrs().to_vec();
+        assert_ne!(addrs[0], addrs[1]);
+        spawn(&mut runtime, server);
+
+        let (sx, rx) = mpsc::unbounded();
+        let executor = runtime.executor();
+        let a = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addrs[0]))
+                    .unwrap()
+                    .serve(executor.clone(), service_fn(|_| Ok(()))),
+            )
+            .unwrap();
+        let _b = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addrs[1]))
+                    .unwrap()
+                    .serve(
+                        executor,
+                        service_fn(move |ctx: Context<ClientEvent>| {
+                            if let ClientEvent::Message(msg) = ctx.message() {
+                                sx.unbounded_send(msg.clone()).unwrap();
+                            }
+                            Ok(())
+                        }),
+                    ),
+            )
+            .unwrap();
+        assert_eq!(handle.connections(), 2);
+
+        runtime
+            .block_on(a.send(MessageContent::Text("hello".to_string())))
+            .unwrap();
+        let (msg, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(msg, Some(MessageContent::Text("hello".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let nowhere: &[std::net::SocketAddr] = &[];
+        for ret in vec![
+            Server::bind(nowhere).map(|_| ()),
+            Server::bind("127.0.0.1:0")
+                .and_then(|server| server.listen(nowhere))
+                .map(|_| ()),
+        ] {
+            match ret {
+                Err(e) => match e.kind() {
+                    JuntaErrorKind::InvalidAddress => {}
+                    _ => panic!("unexpected error {}", e),
+                },
+                Ok(_) => panic!("bound to nothing"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_too_big() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .max_message_size(16)
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if !ctx.message().is_connect() {
+                        sx.unbounded_send(ctx.into_message()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let executor = runtime.executor();
+        let client = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addr))
+                    .unwrap()
+                    .serve(executor, service_fn(|_| Ok(()))),
+            )
+            .unwrap();
+        runtime
+            .block_on(client.send(MessageContent::Text("small".to_string())))
+            .unwrap();
+        runtime
+            .block_on(client.send(MessageContent::Binary(vec![0; 64])))
+            .unwrap();
+
+        let events: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ClientEvent::Message(MessageContent::Text("small".to_string())),
+                ClientEvent::Close(Some(CloseData::new(1009, MESSAGE_TOO_BIG.to_string()))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_on_error() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .on_error(service_fn(|ctx: Context<HandlerError>| {
+                assert!(ctx.message().event().is_message());
+                let client = ctx.client().clone();
+                ctx.client()
+                    .send(MessageContent::Text("failed".to_string()))
+                    .and_then(move |_| {
+                        client.close_with(CloseCode::Application(4000), "HANDLER_ERROR")
+                    })
+            }))
+            .serve(
+                runtime.executor(),
+                service_fn(|ctx: Context<ClientEvent>| match ctx.message() {
+                    ClientEvent::Message(_) => Err(JuntaErrorKind::NotFound.into()),
+                    _ => Ok(()),
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let (sx, rx) = mpsc::unbounded();
+        let executor = runtime.executor();
+        let client = runtime
+            .block_on(Client::connect(format!("ws://{}", addr)).unwrap().serve(
+                executor,
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if !ctx.message().is_connect() {
+                        sx.unbounded_send(ctx.into_message()).unwrap();
+                    }
+                    Ok(())
+                }),
+            ))
+            .unwrap();
+        runtime
+            .block_on(client.send(MessageContent::Text("boom".to_string())))
+            .unwrap();
+
+        let events: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ClientEvent::Message(MessageContent::Text("failed".to_string())),
+                ClientEvent::Close(Some(CloseData::new(4000, "HANDLER_ERROR".to_string()))),
+            ]
+        );
+    }
+}
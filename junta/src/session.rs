@@ -0,0 +1,91 @@
+use super::context::Context;
+use super::error::{JuntaError, JuntaErrorKind};
+use super::plugins::Plugin;
+use std::marker::PhantomData;
+use typemap::Key;
+
+/// Plugin for reading a value from the client's session,
+/// eg. `ctx.get::<Session<UserId>>()`.
+///
+/// The value is cached in the context on first access,
+/// so changes made to the session afterwards are not seen by the same context.
+pub struct Session<K> {
+    _k: PhantomData<K>,
+}
+
+impl<K: Key> Key for Session<K>
+where
+    K::Value: 'static,
+{
+    type Value = K::Value;
+}
+
+impl<K: Key, I> Plugin<Context<I>> for Session<K>
+where
+    K::Value: Clone + Send + Sync,
+{
+    type Error = JuntaError;
+    fn eval(ctx: &mut Context<I>) -> Result<K::Value, JuntaError> {
+        ctx.client()
+            .session()
+            .get::<K>()
+            .cloned()
+            .ok_or_else(|| JuntaErrorKind::NotFound.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugins::Pluggable;
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use futures::sync::mpsc;
+    use junta_service::prelude::*;
+
+    struct Counter;
+
+    impl Key for Counter {
+        type Value = usize;
+    }
+
+    #[test]
+    fn test_session() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(move |mut ctx: Context<ClientEvent>| {
+                    match ctx.message() {
+                        ClientEvent::Connect => {
+                            ctx.client().session_mut().insert::<Counter>(42);
+                        }
+                        ClientEvent::Message(_) => {
+                            sx.unbounded_send(ctx.get::<Session<Counter>>().ok())
+                                .unwrap();
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addr = spawn(&mut runtime, server);
+
+        let client = runtime
+            .block_on(
+                Client::connect(format!("ws://{}", addr))
+                    .unwrap()
+                    .serve(runtime.executor(), service_fn(|_| Ok(()))),
+            )
+            .unwrap();
+        runtime
+            .block_on(client.send(MessageContent::Text("Hello".to_string())))
+            .unwrap();
+
+        let (value, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(value, Some(Some(42)));
+    }
+}
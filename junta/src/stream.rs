@@ -0,0 +1,282 @@
+use super::close::CloseCode;
+use super::codec::Frame;
+use super::error::{JuntaError, JuntaErrorKind};
+use super::outbox::Outbox;
+use futures::prelude::*;
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The close reason sent when a stream passed to `Client::send_stream`
+/// fails or is dropped halfway through the message.
+pub const STREAM_ABORTED: &str = "STREAM_ABORTED";
+
+/// How many received chunks may wait for the handler before reading stops.
+const CHUNKS: usize = 8;
+
+/// The chunks of a binary message which is streamed instead of buffered,
+/// see `ServerBuilder::stream_binary`.
+/// Fails if the connection is lost before the message ends.
+pub struct BinaryStream {
+    chunks: Receiver<Vec<u8>>,
+    complete: Arc<AtomicBool>,
+}
+
+impl Stream for BinaryStream {
+    type Item = Vec<u8>;
+    type Error = JuntaError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.chunks.poll() {
+            Ok(Async::Ready(Some(chunk))) => Ok(Async::Ready(Some(chunk))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(None)) | Err(_) => {
+                if self.complete.load(Ordering::SeqCst) {
+                    Ok(Async::Ready(None))
+                } else {
+                    Err(JuntaErrorKind::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed during a streamed message",
+                    ))
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BinaryStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BinaryStream")
+    }
+}
+
+/// The `BinaryStream` of a `ClientEvent::Stream`, which can be taken once.
+/// Clones of the event share it, so only the first `take` gets the chunks.
+#[derive(Clone)]
+pub struct TakeStream {
+    stream: Arc<Mutex<Option<BinaryStream>>>,
+}
+
+impl TakeStream {
+    pub(crate) fn new(stream: BinaryStream) -> TakeStream {
+        TakeStream {
+            stream: Arc::new(Mutex::new(Some(stream))),
+        }
+    }
+
+    /// Takes the stream, `None` if it was already taken.
+    pub fn take(&self) -> Option<BinaryStream> {
+        self.stream.lock().unwrap().take()
+    }
+}
+
+impl PartialEq for TakeStream {
+    fn eq(&self, other: &TakeStream) -> bool {
+        Arc::ptr_eq(&self.stream, &other.stream)
+    }
+}
+
+impl fmt::Debug for TakeStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TakeStream")
+    }
+}
+
+/// The connection's end of a `BinaryStream`.
+pub(crate) struct Upload {
+    chunks: Sender<Vec<u8>>,
+    complete: Arc<AtomicBool>,
+}
+
+impl Upload {
+    pub(crate) fn new() -> (Upload, BinaryStream) {
+        let (sx, rx) = channel(CHUNKS);
+        let complete = Arc::new(AtomicBool::new(false));
+        let upload = Upload {
+            chunks: sx,
+            complete: complete.clone(),
+        };
+        let stream = BinaryStream {
+            chunks: rx,
+            complete,
+        };
+        (upload, stream)
+    }
+
+    /// Hands a chunk to the handler, `finished` marks the last one.
+    /// Fails if the handler dropped the stream.
+    pub(crate) fn start_send(
+        &mut self,
+        chunk: Vec<u8>,
+        finished: bool,
+    ) -> Result<AsyncSink<Vec<u8>>, ()> {
+        let ret = self.chunks.start_send(chunk).map_err(|_| ())?;
+        if finished && ret.is_ready() {
+            self.complete.store(true, Ordering::SeqCst);
+        }
+        Ok(ret)
+    }
+}
+
+/// Future returned by `Client::send_stream`.
+pub struct StreamFuture<S> {
+    outbox: Arc<Outbox>,
+    chunks: S,
+    pending: Option<Frame>,
+    /// Holding the outbox, so no other message goes out between the chunks
+    started: bool,
+    first: bool,
+    done: bool,
+    bytes: usize,
+}
+
+impl<S> StreamFuture<S> {
+    pub(crate) fn new(outbox: Arc<Outbox>, chunks: S) -> StreamFuture<S> {
+        StreamFuture {
+            outbox,
+            chunks,
+            pending: None,
+            started: false,
+            first: true,
+            done: false,
+            bytes: 0,
+        }
+    }
+}
+
+impl<S> Future for StreamFuture<S>
+where
+    S: Stream<Item = Vec<u8>, Error = JuntaError>,
+{
+    /// The size of the message.
+    type Item = usize;
+    type Error = JuntaError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.started {
+            if let Async::NotReady = self.outbox.poll_begin_stream()? {
+                return Ok(Async::NotReady);
+            }
+            self.started = true;
+        }
+        loop {
+            if self.pending.is_some() {
+                if let Async::NotReady = self.outbox.poll_push_chunk(&mut self.pending)? {
+                    return Ok(Async::NotReady);
+                }
+                self.first = false;
+            }
+            if self.done {
+                self.outbox.end_stream();
+                self.started = false;
+                return Ok(Async::Ready(self.bytes));
+            }
+            let (data, finished) = match self.chunks.poll()? {
+                Async::Ready(Some(data)) => (data, false),
+                // Ends the message with an empty frame, as the last chunk is only known now
+                Async::Ready(None) => (Vec::new(), true),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            self.bytes += data.len();
+            self.done = finished;
+            self.pending = Some(Frame::Chunk {
+                data,
+                first: self.first,
+                finished,
+            });
+        }
+    }
+}
+
+impl<S> Drop for StreamFuture<S> {
+    fn drop(&mut self) {
+        if !self.started {
+            return;
+        }
+        if self.first {
+            self.outbox.end_stream();
+        } else {
+            // The peer can not tell a cut off message from a whole one
+            self.outbox
+                .disconnect(CloseCode::InternalError.with_reason(STREAM_ABORTED));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use futures::sync::mpsc;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_stream_binary() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let loopback = Server::loopback().stream_binary(4).serve_loopback(
+            runtime.executor(),
+            service_fn(move |ctx: Context<ClientEvent>| {
+                let sx = sx.clone();
+                let client = ctx.client().clone();
+                let fut: Box<Future<Item = (), Error = JuntaError> + Send> = match ctx.message() {
+                    ClientEvent::Stream(stream) => {
+                        let chunks = stream.take().unwrap();
+                        // Clones share the stream, so it is only taken once
+                        assert!(stream.clone().take().is_none());
+                        Box::new(chunks.collect().and_then(move |chunks| {
+                            sx.unbounded_send(chunks.clone()).unwrap();
+                            // Echo it back in two frames
+                            let data: Vec<u8> = chunks.concat();
+                            let (head, tail) = data.split_at(2);
+                            let echo = futures::stream::iter_ok(vec![head.to_vec(), tail.to_vec()]);
+                            client.send_stream(echo).map(|_| ())
+                        }))
+                    }
+                    ClientEvent::Message(MessageContent::Binary(data)) => {
+                        sx.unbounded_send(vec![data.clone()]).unwrap();
+                        Box::new(futures::future::ok(()))
+                    }
+                    _ => Box::new(futures::future::ok(())),
+                };
+                fut
+            }),
+        );
+        let handle = loopback.handle();
+        let (_, client) = loopback.connect();
+
+        // Under the threshold, so buffered as usual
+        let chunks = futures::stream::iter_ok(vec![vec![1, 2], vec![3]]);
+        let size = runtime
+            .block_on(client.client().send_stream(chunks))
+            .unwrap();
+        assert_eq!(size, 3);
+        let (chunks, rx) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(chunks, Some(vec![vec![1, 2, 3]]));
+
+        let chunks = futures::stream::iter_ok(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+        runtime
+            .block_on(client.client().send_stream(chunks))
+            .unwrap();
+        let (chunks, _) = runtime.block_on(rx.into_future()).ok().unwrap();
+        assert_eq!(
+            chunks,
+            Some(vec![vec![1, 2, 3, 4, 5, 6], vec![7], Vec::new()])
+        );
+        // Counted once, like a buffered message
+        let text = handle.metrics().render();
+        assert!(text.contains("junta_messages_received_total{type=\"binary\"} 2\n"));
+        assert!(text.contains("junta_received_bytes_total{type=\"binary\"} 10\n"));
+
+        let (event, _) = runtime.block_on(client.into_future()).ok().unwrap();
+        assert_eq!(
+            event,
+            Some(ClientEvent::Message(MessageContent::Binary(vec![
+                1, 2, 3, 4, 5, 6, 7
+            ])))
+        );
+    }
+}
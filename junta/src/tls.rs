@@ -0,0 +1,96 @@
+use super::error::JuntaResult;
+use native_tls::{Identity, TlsAcceptor as NativeTlsAcceptor};
+use tokio_tls::TlsAcceptor;
+
+/// A certificate and private key used to accept `wss://` connections.
+pub struct TlsIdentity {
+    identity: Identity,
+}
+
+impl TlsIdentity {
+    /// Load an identity from a DER-formatted PKCS #12 archive.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> JuntaResult<TlsIdentity> {
+        Ok(TlsIdentity {
+            identity: Identity::from_pkcs12(der, password)?,
+        })
+    }
+
+    /// Load an identity from a PEM-formatted certificate chain and a
+    /// PEM-formatted PKCS #8 private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> JuntaResult<TlsIdentity> {
+        Ok(TlsIdentity {
+            identity: Identity::from_pkcs8(cert, key)?,
+        })
+    }
+
+    pub(crate) fn acceptor(self) -> JuntaResult<TlsAcceptor> {
+        Ok(TlsAcceptor::from(NativeTlsAcceptor::new(self.identity)?))
+    }
+}
+
+impl From<Identity> for TlsIdentity {
+    fn from(identity: Identity) -> TlsIdentity {
+        TlsIdentity { identity }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::server::tests::spawn;
+    use futures::sync::mpsc;
+    use junta_service::prelude::*;
+
+    #[test]
+    fn test_wss_roundtrip() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let identity = TlsIdentity::from_pem(
+            include_bytes!("../tests/fixtures/cert.pem"),
+            include_bytes!("../tests/fixtures/key.pem"),
+        )
+        .unwrap();
+
+        let (sx, rx) = mpsc::unbounded();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .tls(identity)
+            .listen("127.0.0.1:0")
+            .unwrap()
+            .serve(
+                runtime.executor(),
+                service_fn(move |ctx: Context<ClientEvent>| {
+                    if let ClientEvent::Message(msg) = ctx.message() {
+                        sx.unbounded_send(msg.clone()).unwrap();
+                    }
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        let addrs = server.local_addrs().to_vec();
+        spawn(&mut runtime, server);
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        // Both listeners are secured
+        for addr in &addrs {
+            let client = runtime
+                .block_on(
+                    Client::connect(format!("wss://{}", addr))
+                        .unwrap()
+                        .tls(connector.clone())
+                        .serve(runtime.executor(), service_fn(|_| Ok(()))),
+                )
+                .unwrap();
+            runtime
+                .block_on(client.send(MessageContent::Text(addr.to_string())))
+                .unwrap();
+        }
+        let received: Vec<_> = runtime.block_on(rx.take(2).collect()).unwrap();
+        for addr in &addrs {
+            assert!(received.contains(&MessageContent::Text(addr.to_string())));
+        }
+    }
+}